@@ -0,0 +1,149 @@
+// compares how many jobs a second the pool gets through against the old design,
+// where every worker took turns locking one shared mpsc::Receiver.
+//
+// run with `cargo bench`. no external crates, so it's just timing loops and printing.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_webserver::ThreadPool;
+
+const JOBS: usize = 200_000;
+const WORKER_COUNTS: [usize; 4] = [1, 4, 8, 16];
+// take the best of a few runs so one hiccup doesn't skew it
+const RUNS: usize = 3;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+// what each job does, returns something so it can't be optimised away
+type Work = fn() -> u64;
+
+// the pool as it was in the beginning: every worker takes turns locking one shared
+// mpsc::Receiver, and execute does nothing but send. the same handoff exactly, only
+// without the println per job, which was far slower than anything measured here.
+enum Message {
+    NewJob(Job),
+    Terminate
+}
+
+struct ChannelPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: mpsc::Sender<Message>
+}
+
+impl ChannelPool {
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv().unwrap();
+
+                    match message {
+                        Message::NewJob(job) => job(),
+                        Message::Terminate => break
+                    }
+                })
+            })
+            .collect();
+
+        ChannelPool { workers, sender }
+    }
+
+    fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.sender.send(Message::NewJob(Box::new(f))).unwrap();
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        for _ in &self.workers {
+            self.sender.send(Message::Terminate).unwrap();
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+fn empty_work() -> u64 {
+    0
+}
+
+// a bit of busywork so the job isn't free, roughly a microsecond
+fn small_work() -> u64 {
+    let mut x: u64 = 1;
+    for i in 0..200 {
+        x = x.wrapping_mul(6364136223846793005).wrapping_add(i);
+    }
+    std::hint::black_box(x)
+}
+
+// pushes JOBS jobs through `execute` and waits until they've all run
+fn run<E: Fn(Job)>(execute: E, work: Work) -> Duration {
+    let done = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+
+    for _ in 0..JOBS {
+        let done = Arc::clone(&done);
+        execute(Box::new(move || {
+            work();
+            done.fetch_add(1, Ordering::Relaxed);
+        }));
+    }
+
+    while done.load(Ordering::Relaxed) < JOBS {
+        thread::yield_now();
+    }
+
+    started.elapsed()
+}
+
+fn best_of<F: FnMut() -> Duration>(mut f: F) -> Duration {
+    (0..RUNS).map(|_| f()).min().unwrap()
+}
+
+fn jobs_per_sec(took: Duration) -> f64 {
+    JOBS as f64 / took.as_secs_f64()
+}
+
+fn main() {
+    let workloads: [(&str, Work); 2] = [("empty job", empty_work), ("~1us job", small_work)];
+
+    // with one cpu the workers never fight over the receiver, which is the whole
+    // point of comparing, so say how many there were alongside the numbers
+    let cpus = thread::available_parallelism().map(|cpus| cpus.get()).unwrap_or(1);
+    println!("{} jobs per run, best of {}, {} cpus", JOBS, RUNS, cpus);
+    if cpus == 1 {
+        println!("only one cpu, so this can't show contention between workers");
+    }
+    println!();
+    println!("{:<10} {:>8} {:>16} {:>16} {:>8}", "workload", "workers", "channel jobs/s", "pool jobs/s", "speedup");
+
+    for (name, work) in workloads {
+        for workers in WORKER_COUNTS {
+            let channel = best_of(|| {
+                let pool = ChannelPool::new(workers);
+                run(|job| pool.execute(job), work)
+            });
+            let sharded = best_of(|| {
+                let pool = ThreadPool::new(workers);
+                run(|job| pool.execute(job), work)
+            });
+
+            println!(
+                "{:<10} {:>8} {:>16.0} {:>16.0} {:>7.2}x",
+                name,
+                workers,
+                jobs_per_sec(channel),
+                jobs_per_sec(sharded),
+                channel.as_secs_f64() / sharded.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::queue::JobQueue;
+use crate::{default_panic_handler, Backlog, InFlight, PanicHandler, Shared, ThreadHook, ThreadPool};
+
+/// Everything that can go wrong setting up a `ThreadPool`.
+#[derive(Debug)]
+pub enum BuildError {
+    /// A pool with no threads would never run anything.
+    ZeroSize,
+    /// The min size is bigger than the max size.
+    MinAboveMax,
+    /// A bounded queue with no room would turn every job away.
+    ZeroCapacity,
+    /// The os wouldn't give us a thread, e.g. because the stack size was too big.
+    Spawn(io::Error)
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            BuildError::MinAboveMax => write!(f, "thread pool min size must not be greater than its max size"),
+            BuildError::ZeroCapacity => write!(f, "thread pool queue capacity must be greater than zero"),
+            BuildError::Spawn(err) => write!(f, "failed to spawn worker thread: {}", err)
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// how long a worker above the min size can sit idle before it's reaped, unless told otherwise
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+// how long a low priority job can be passed over for higher priority ones, unless told otherwise
+const DEFAULT_STARVATION_LIMIT: Duration = Duration::from_secs(1);
+
+/// Sets up a `ThreadPool` with more control than `ThreadPool::new` gives.
+pub struct ThreadPoolBuilder {
+    min_size: usize,
+    max_size: usize,
+    keep_alive: Duration,
+    queue_capacity: Option<usize>,
+    starvation_limit: Duration,
+    thread_name_prefix: String,
+    stack_size: Option<usize>,
+    panic_handler: Option<PanicHandler>,
+    on_thread_start: Option<ThreadHook>,
+    on_thread_stop: Option<ThreadHook>
+}
+
+impl ThreadPoolBuilder {
+    /// Start with a fixed one thread per cpu, an unbounded queue and threads named `worker-<id>`.
+    pub fn new() -> ThreadPoolBuilder {
+        let size = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+        ThreadPoolBuilder {
+            min_size: size,
+            max_size: size,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            queue_capacity: None,
+            starvation_limit: DEFAULT_STARVATION_LIMIT,
+            thread_name_prefix: String::from("worker-"),
+            stack_size: None,
+            panic_handler: None,
+            on_thread_start: None,
+            on_thread_stop: None
+        }
+    }
+
+    /// The number of threads in the pool, fixed at that.
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.min_size = size;
+        self.max_size = size;
+        self
+    }
+
+    /// The fewest threads the pool keeps around, even when there's nothing to do.
+    /// This many are started up front.
+    pub fn min_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.min_size = size;
+        self
+    }
+
+    /// The most threads the pool will grow to while jobs are backing up.
+    pub fn max_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.max_size = size;
+        self
+    }
+
+    /// How long a thread above the min size can go without a job before it's stopped.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Hold at most `capacity` jobs waiting for a worker, see `ThreadPool::bounded`.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// How long a job can wait while higher priority ones keep going ahead of
+    /// it, before it's run ahead of them instead. Defaults to a second.
+    pub fn starvation_limit(mut self, limit: Duration) -> ThreadPoolBuilder {
+        self.starvation_limit = limit;
+        self
+    }
+
+    /// Name each thread `prefix` followed by its worker id, so they can be told
+    /// apart in `top`, debuggers and panic messages.
+    pub fn thread_name<S: Into<String>>(mut self, prefix: S) -> ThreadPoolBuilder {
+        self.thread_name_prefix = prefix.into();
+        self
+    }
+
+    /// The stack size of each thread in bytes, instead of the platform default.
+    pub fn stack_size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Call `handler` whenever a job panics, see `ThreadPool::with_panic_handler`.
+    pub fn panic_handler<F>(mut self, handler: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static
+    {
+        self.panic_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Run `hook` on each new worker thread before it starts taking jobs,
+    /// e.g. to set up thread local state like a database connection.
+    ///
+    /// Gets the worker's id. Also runs for workers respawned after dying.
+    pub fn on_thread_start<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static
+    {
+        self.on_thread_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Run `hook` on each worker thread just before it exits, to tear down
+    /// whatever `on_thread_start` set up. Runs even if the thread is dying from a panic.
+    pub fn on_thread_stop<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static
+    {
+        self.on_thread_stop = Some(Arc::new(hook));
+        self
+    }
+
+    /// Spawn the worker threads and hand back the pool.
+    pub fn build(self) -> Result<ThreadPool, BuildError> {
+        if self.max_size == 0 {
+            return Err(BuildError::ZeroSize);
+        }
+        if self.min_size > self.max_size {
+            return Err(BuildError::MinAboveMax);
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(BuildError::ZeroCapacity);
+        }
+
+        // a shard per worker so with a full pool each one has a home shard to itself, but no
+        // more than there are cpus. past that it's just more empty shards to look through.
+        let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+        let shared = Arc::new(Shared {
+            // is a little bit more efficient to pre-allocate the memory here with #with_capacity
+            workers: Mutex::new(Vec::with_capacity(self.max_size)),
+            queue: JobQueue::new(self.max_size.min(cpus), self.starvation_limit),
+            panic_handler: self.panic_handler.unwrap_or_else(default_panic_handler),
+            backlog: Backlog::new(self.queue_capacity),
+            in_flight: InFlight::new(),
+            min_size: AtomicUsize::new(self.min_size),
+            max_size: AtomicUsize::new(self.max_size),
+            keep_alive: self.keep_alive,
+            live: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            stopped: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+            panicked: AtomicUsize::new(0),
+            cancelled: AtomicUsize::new(0),
+            timed_out: AtomicUsize::new(0),
+            next_id: AtomicUsize::new(0),
+            thread_name_prefix: self.thread_name_prefix,
+            stack_size: self.stack_size,
+            on_thread_start: self.on_thread_start,
+            on_thread_stop: self.on_thread_stop
+        });
+
+        let pool = ThreadPool { shared, scheduler: OnceLock::new() };
+
+        {
+            let mut workers = pool.shared.workers.lock().unwrap();
+            for _ in 0..self.min_size {
+                // dropping the pool on the way out shuts down whichever workers did manage to start
+                pool.shared.spawn_worker(&mut workers).map_err(BuildError::Spawn)?;
+            }
+        }
+
+        Ok(pool)
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+}
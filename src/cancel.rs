@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Lets a job be called off, see `ThreadPool::execute_with_token`.
+///
+/// Clones all share the same state, so one can be handed to the job and
+/// another kept to cancel it with. A job that hasn't started yet when it's
+/// cancelled is thrown away without running. One that's already running
+/// has to check `is_cancelled` itself every so often and stop early.
+///
+/// A token can also have a deadline, after which it counts as cancelled
+/// and the job is reported as timed out.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    deadline: Option<Instant>
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// A token that cancels itself at `deadline`.
+    pub fn with_deadline(deadline: Instant) -> CancellationToken {
+        CancellationToken { inner: Arc::new(Inner { cancelled: AtomicBool::new(false), deadline: Some(deadline) }) }
+    }
+
+    /// A token that cancels itself once `timeout` has passed from now.
+    pub fn with_timeout(timeout: Duration) -> CancellationToken {
+        CancellationToken::with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the job should stop, either because `cancel` was called or the deadline has passed.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst) || self.is_timed_out()
+    }
+
+    /// Whether the deadline has passed. Always false without one.
+    pub fn is_timed_out(&self) -> bool {
+        self.inner.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::{self, BufRead, Write};
+
+use crate::request::{self, ParseError};
+
+/// Decode a `Transfer-Encoding: chunked` body from `reader`.
+///
+/// Each chunk is a hex size line followed by that many bytes, and a zero size
+/// chunk ends the body. Gives up with `ParseError::PayloadTooLarge` as soon as
+/// the chunks add up to more than `max_body_size`, rather than reading the lot first.
+pub(crate) fn read_chunked_body<R: BufRead>(reader: &mut R, max_body_size: usize) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = match request::read_line(reader)? {
+            Some(line) => line,
+            None => return Err(request::unexpected_eof().into())
+        };
+
+        // chunk extensions (`1a;name=value`) are allowed but nobody uses them, just skip past
+        let size = line.split(';').next().unwrap_or("").trim();
+        // from_str_radix would also let through a leading +, which isn't valid here
+        let size = match u64::from_str_radix(size, 16) {
+            Ok(parsed) if size.bytes().all(|b| b.is_ascii_hexdigit()) => parsed,
+            _ => return Err(ParseError::InvalidChunk(line))
+        };
+
+        if size == 0 {
+            break;
+        }
+
+        // saturating, a huge size after an earlier chunk would overflow otherwise
+        let total = (body.len() as u64).saturating_add(size);
+        if total > max_body_size as u64 {
+            return Err(ParseError::PayloadTooLarge(total));
+        }
+
+        let start = body.len();
+        body.resize(start + size as usize, 0);
+        reader.read_exact(&mut body[start..])?;
+
+        // every chunk's data is followed by its own \r\n
+        match request::read_line(reader)? {
+            Some(line) if line.is_empty() => {},
+            Some(line) => return Err(ParseError::InvalidChunk(line)),
+            None => return Err(request::unexpected_eof().into())
+        }
+    }
+
+    // after the last chunk there can be trailer headers, then a blank line.
+    // we don't do anything with trailers, they just need to come off the stream,
+    // but there's only so many of them we'll read, same as headers.
+    let mut trailers = 0;
+    loop {
+        match request::read_line(reader)? {
+            Some(line) if line.is_empty() => break,
+            Some(_) if trailers == request::MAX_HEADERS => return Err(ParseError::TooManyHeaders),
+            Some(_) => trailers += 1,
+            None => return Err(request::unexpected_eof().into())
+        }
+    }
+
+    Ok(body)
+}
+
+/// Writes a body using `Transfer-Encoding: chunked`.
+///
+/// Every `write` goes out as its own chunk, so wrap a `BufWriter` around this
+/// if you're going to be doing lots of tiny writes. The body isn't finished
+/// until `finish` is called, which sends the terminating zero size chunk.
+pub struct ChunkedWriter<W: Write> {
+    inner: W
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter { inner }
+    }
+
+    /// Write the last chunk and hand back the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a zero length chunk would end the body early, so just don't send one
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &str, max_body_size: usize) -> Result<Vec<u8>, ParseError> {
+        read_chunked_body(&mut raw.as_bytes(), max_body_size)
+    }
+
+    #[test]
+    fn decodes_chunks_extensions_and_trailers() {
+        let body = read("4\r\nWiki\r\n5;name=value\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nX-Trailer: yes\r\n\r\n", 1024);
+        assert_eq!(body.unwrap(), b"Wikipedia in\r\n\r\nchunks.");
+    }
+
+    #[test]
+    fn leaves_whatever_follows_in_the_reader() {
+        let mut reader = "3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1".as_bytes();
+        assert_eq!(read_chunked_body(&mut reader, 1024).unwrap(), b"abc");
+        assert_eq!(reader, b"GET / HTTP/1.1");
+    }
+
+    #[test]
+    fn rejects_bad_chunk_sizes() {
+        for size in ["", "g", "+3", "-3", "0x3", "3 3", "10000000000000000"] {
+            let raw = format!("{}\r\nabc\r\n0\r\n\r\n", size);
+            assert!(matches!(read(&raw, 1024), Err(ParseError::InvalidChunk(_))), "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn chunk_data_has_to_end_where_its_size_says() {
+        assert!(matches!(read("3\r\nabcd\r\n0\r\n\r\n", 1024), Err(ParseError::InvalidChunk(_))));
+    }
+
+    #[test]
+    fn stops_as_soon_as_the_body_is_too_big() {
+        assert!(matches!(read("8\r\n12345678\r\n8\r\n12345678\r\n0\r\n\r\n", 10), Err(ParseError::PayloadTooLarge(16))));
+        // a size near u64::MAX after an earlier chunk mustn't overflow
+        let huge = read("1\r\na\r\nffffffffffffffff\r\n", 10);
+        assert!(matches!(huge, Err(ParseError::PayloadTooLarge(u64::MAX))));
+    }
+
+    #[test]
+    fn stops_reading_trailers_after_as_many_as_headers() {
+        let trailers = "X-Trailer: yes\r\n".repeat(request::MAX_HEADERS);
+        let body = read(&format!("3\r\nabc\r\n0\r\n{}\r\n", trailers), 1024);
+        assert_eq!(body.unwrap(), b"abc");
+
+        // an endless run of them has to stop somewhere
+        let endless = "X-Trailer: yes\r\n".repeat(request::MAX_HEADERS * 10);
+        let body = read(&format!("3\r\nabc\r\n0\r\n{}", endless), 1024);
+        assert!(matches!(body, Err(ParseError::TooManyHeaders)));
+    }
+
+    #[test]
+    fn a_cut_off_body_is_an_error() {
+        for raw in ["3\r\nab", "3\r\nabc\r\n", "3\r\nabc\r\n0\r\n"] {
+            assert!(matches!(read(raw, 1024), Err(ParseError::Io(_))), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn writer_round_trips_through_the_reader() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"world").unwrap();
+        let encoded = writer.finish().unwrap();
+
+        assert_eq!(encoded, b"6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n");
+        assert_eq!(read_chunked_body(&mut &encoded[..], 1024).unwrap(), b"hello world");
+    }
+}
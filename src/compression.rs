@@ -0,0 +1,672 @@
+//! Compressing responses with gzip or deflate for clients that say they
+//! can take it in `Accept-Encoding`.
+//!
+//! The compressor is our own, a single block of LZ77 with deflate's fixed
+//! Huffman codes. It doesn't squeeze as hard as zlib does, but it gets most
+//! of the way there on text, which is all we bother compressing anyway.
+
+use std::io::Read;
+
+use crate::conditional;
+use crate::headers::Headers;
+use crate::request::Request;
+use crate::response::{Body, Response};
+use crate::status::StatusCode;
+
+// smaller than this and the gzip header and trailer eat most of the savings
+const MIN_SIZE: u64 = 256;
+// bodies are compressed in memory, so don't take on anything too big
+const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+// the sliding window deflate lets matches reach back over
+const WINDOW_SIZE: usize = 32 * 1024;
+const HASH_BITS: u32 = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// how many earlier spots with the same hash to try before settling for the best so far
+const MAX_CHAIN: usize = 64;
+// the most a stored block can hold, its length is 16 bits
+const STORED_BLOCK_SIZE: usize = 65535;
+
+// the length and distance codes, as the base value each starts at and how
+// many extra bits follow it (RFC 1951 section 3.2.5)
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// A content coding we know how to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Gzip,
+    /// Confusingly, the `deflate` coding is deflate inside a zlib wrapper, not bare deflate.
+    Deflate
+}
+
+impl Encoding {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate"
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => gzip(data),
+            Encoding::Deflate => zlib(data)
+        }
+    }
+}
+
+// decide whether `response` is going out compressed, and if so set up its
+// headers to say so. the body is left alone until `encode`, so a response that
+// turns into a 304 never has to be compressed at all.
+//
+// this has to happen before anything looks at the ETag, since each encoding
+// is a different representation and needs an ETag of its own.
+pub(crate) fn negotiate(request: &Request, response: &mut Response) -> Option<Encoding> {
+    if response.status != StatusCode::Ok || response.headers.contains("Content-Encoding") {
+        return None;
+    }
+
+    if !response.headers.get("Content-Type").is_some_and(compressible) {
+        return None;
+    }
+
+    let length = match &response.body {
+        Body::Bytes(contents) => contents.len() as u64,
+        Body::File(file) => file.metadata().ok()?.len(),
+        // no telling how big it is without reading it all
+        Body::Stream(_) => return None
+    };
+    if !(MIN_SIZE..=MAX_SIZE).contains(&length) {
+        return None;
+    }
+
+    // caches have to know this could have gone out differently, even to clients that didn't ask
+    add_vary(&mut response.headers, "Accept-Encoding");
+
+    let accepted: Vec<&str> = request.headers.get_all("Accept-Encoding").collect();
+    let encoding = preferred(&accepted.join(", "))?;
+
+    conditional::add_etag(response);
+    if let Some(etag) = response.headers.get("ETag") {
+        let etag = tag_etag(etag, encoding);
+        response.headers.insert("ETag", &etag);
+    }
+    response.headers.insert("Content-Encoding", encoding.as_str());
+
+    Some(encoding)
+}
+
+// compress the body of a response `negotiate` picked an encoding for, as long
+// as it's still the 200 it was then
+pub(crate) fn encode(response: Response, encoding: Option<Encoding>) -> Response {
+    let encoding = match encoding {
+        Some(encoding) if response.status == StatusCode::Ok => encoding,
+        _ => return response
+    };
+
+    let Response { status, headers, body } = response;
+
+    let contents = match body {
+        Body::Bytes(contents) => contents,
+        Body::File(mut file) => {
+            let mut contents = Vec::new();
+            if let Err(err) = file.read_to_end(&mut contents) {
+                println!("Couldn't read a file to compress it: {}", err);
+                return Response::new(StatusCode::InternalServerError);
+            }
+            contents
+        },
+        Body::Stream(_) => unreachable!("streams are never picked for compressing")
+    };
+
+    Response { status, headers, body: Body::Bytes(encoding.encode(&contents)) }
+}
+
+// whether the client takes gzip at all, for deciding on a precompressed file
+pub(crate) fn accepts_gzip(request: &Request) -> bool {
+    let accepted: Vec<&str> = request.headers.get_all("Accept-Encoding").collect();
+    quality(&accepted.join(", "), "gzip") > 0.0
+}
+
+/// Add `name` to the `Vary` header, unless it's already listed there.
+pub(crate) fn add_vary(headers: &mut Headers, name: &str) {
+    let vary = match headers.get("Vary") {
+        Some(vary) => vary.to_string(),
+        None => return headers.insert("Vary", name)
+    };
+
+    let listed = vary.split(',').map(str::trim).any(|value| value == "*" || value.eq_ignore_ascii_case(name));
+    if !listed {
+        headers.insert("Vary", &format!("{}, {}", vary, name));
+    }
+}
+
+// text compresses well. images, video, fonts and archives mostly already are compressed.
+fn compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm"
+        )
+}
+
+// the encoding the client would most like out of the ones we have, gzip if it likes both the same
+fn preferred(accept_encoding: &str) -> Option<Encoding> {
+    let gzip = quality(accept_encoding, "gzip");
+    let deflate = quality(accept_encoding, "deflate");
+
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+// the q value `accept_encoding` gives `coding`, e.g. 0.5 for gzip in `gzip;q=0.5, br`.
+// falls back on `*` if the coding isn't listed by name, and 0 if neither is.
+fn quality(accept_encoding: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+        if name.is_empty() {
+            continue;
+        }
+
+        let mut q = 1.0;
+        for param in params {
+            if let Some((key, value)) = param.split_once('=') {
+                if key.trim().eq_ignore_ascii_case("q") {
+                    q = value.trim().parse().unwrap_or(0.0);
+                }
+            }
+        }
+
+        // x-gzip is what gzip was called once, and still has to be treated the same
+        if name.eq_ignore_ascii_case(coding) || (coding == "gzip" && name.eq_ignore_ascii_case("x-gzip")) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+
+    wildcard.unwrap_or(0.0)
+}
+
+// `"abc"` becomes `"abc-gzip"`, weak ones stay weak
+fn tag_etag(etag: &str, encoding: Encoding) -> String {
+    match etag.strip_suffix('"') {
+        Some(start) => format!("{}-{}\"", start, encoding.as_str()),
+        None => etag.to_string()
+    }
+}
+
+/// Compress `data` into the gzip format (RFC 1952).
+fn gzip(data: &[u8]) -> Vec<u8> {
+    // magic number, deflate, no flags, no modification time, no extra flags, unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    // the length is only kept mod 2^32, truncating is what's meant to happen
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Compress `data` into the zlib format (RFC 1950), which is what the
+/// `deflate` content coding actually means.
+fn zlib(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, then the check bits that make the pair a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate(data));
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+/// Compress `data` as a raw deflate stream (RFC 1951), all in one block
+/// using the fixed Huffman codes. Anything that comes out bigger than it went
+/// in, like something already compressed, is sent as stored blocks instead.
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let compressed = compress(data);
+
+    // each stored block costs five bytes on top of what's in it
+    let stored_length = data.len() + 5 * data.len().div_ceil(STORED_BLOCK_SIZE).max(1);
+    if compressed.len() > stored_length {
+        stored(data)
+    } else {
+        compressed
+    }
+}
+
+// stored blocks are just the bytes as they are, with the length in front
+fn stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5);
+    let mut blocks = data.chunks(STORED_BLOCK_SIZE).peekable();
+
+    while let Some(block) = blocks.next() {
+        // BFINAL on the last one, BTYPE 00, then padding out to the byte
+        out.push(u8::from(blocks.peek().is_none()));
+        let length = block.len() as u16;
+        out.extend(length.to_le_bytes());
+        out.extend((!length).to_le_bytes());
+        out.extend(block);
+    }
+
+    out
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::new();
+    // the only block, and it uses the fixed codes
+    out.write(1, 1);
+    out.write(1, 2);
+
+    let mut matcher = Matcher::new(data);
+    let mut position = 0;
+
+    while position < data.len() {
+        match matcher.longest_match(position) {
+            Some((length, distance)) => {
+                write_length(&mut out, length);
+                write_distance(&mut out, distance);
+
+                for skipped in position..position + length {
+                    matcher.insert(skipped);
+                }
+                position += length;
+            },
+            None => {
+                write_literal(&mut out, u16::from(data[position]));
+                matcher.insert(position);
+                position += 1;
+            }
+        }
+    }
+
+    // end of block
+    write_literal(&mut out, 256);
+    out.finish()
+}
+
+// finds earlier copies of what's coming up, by chaining together every
+// position whose next three bytes hash the same
+struct Matcher<'a> {
+    data: &'a [u8],
+    // the latest position for each hash
+    head: Vec<usize>,
+    // the position before it with the same hash, indexed by position mod the window
+    previous: Vec<usize>
+}
+
+impl<'a> Matcher<'a> {
+    fn new(data: &'a [u8]) -> Matcher<'a> {
+        Matcher { data, head: vec![usize::MAX; 1 << HASH_BITS], previous: vec![usize::MAX; WINDOW_SIZE] }
+    }
+
+    fn hash(&self, position: usize) -> usize {
+        let bytes = &self.data[position..position + MIN_MATCH];
+        let hash = (u32::from(bytes[0]) << 10) ^ (u32::from(bytes[1]) << 5) ^ u32::from(bytes[2]);
+        (hash & ((1 << HASH_BITS) - 1)) as usize
+    }
+
+    fn insert(&mut self, position: usize) {
+        if position + MIN_MATCH > self.data.len() {
+            return;
+        }
+
+        let hash = self.hash(position);
+        self.previous[position % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = position;
+    }
+
+    // the longest (length, distance) match for what starts at `position`, if there's one worth using
+    fn longest_match(&self, position: usize) -> Option<(usize, usize)> {
+        if position + MIN_MATCH > self.data.len() {
+            return None;
+        }
+
+        let longest_possible = (self.data.len() - position).min(MAX_MATCH);
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(position)];
+
+        for _ in 0..MAX_CHAIN {
+            // usize::MAX is the end of the chain, and anything too far back is out of reach
+            if candidate >= position || position - candidate > WINDOW_SIZE {
+                break;
+            }
+
+            let length = self.data[candidate..]
+                .iter()
+                .zip(&self.data[position..position + longest_possible])
+                .take_while(|(earlier, now)| earlier == now)
+                .count();
+
+            if length >= MIN_MATCH && best.is_none_or(|(best, _)| length > best) {
+                best = Some((length, position - candidate));
+                if length == longest_possible {
+                    break;
+                }
+            }
+
+            // once the window wraps, a slot can have been reused by a later position,
+            // which shows up as the chain going forwards instead of back
+            let next = self.previous[candidate % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+
+        best
+    }
+}
+
+// a literal byte, or 256 for the end of the block, or a length code
+fn write_literal(out: &mut BitWriter, symbol: u16) {
+    let (code, bits) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8)
+    };
+    out.write_code(code, bits);
+}
+
+fn write_length(out: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASE.partition_point(|&base| usize::from(base) <= length) - 1;
+    write_literal(out, 257 + index as u16);
+    out.write((length - usize::from(LENGTH_BASE[index])) as u32, u32::from(LENGTH_EXTRA[index]));
+}
+
+fn write_distance(out: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASE.partition_point(|&base| usize::from(base) <= distance) - 1;
+    // distance codes are all five bits with the fixed codes
+    out.write_code(index as u16, 5);
+    out.write((distance - usize::from(DISTANCE_BASE[index])) as u32, u32::from(DISTANCE_EXTRA[index]));
+}
+
+// deflate packs bits starting from the lowest bit of each byte
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { out: Vec::new(), bits: 0, count: 0 }
+    }
+
+    // the lowest `count` bits of `value`, lowest first
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // huffman codes are the odd one out and go highest bit first
+    fn write_code(&mut self, code: u16, count: u32) {
+        let reversed = u32::from(code.reverse_bits() >> (16 - count));
+        self.write(reversed, count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+
+    // 5552 is the most bytes that can be added up before b could overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // just enough of an inflater to check what we write, stored and fixed huffman blocks only
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = BitReader { data, position: 0 };
+        let mut out = Vec::new();
+
+        loop {
+            let last = reader.bits(1) == 1;
+            match reader.bits(2) {
+                0 => {
+                    reader.align();
+                    let length = reader.bits(16) as usize;
+                    let inverse = reader.bits(16) as usize;
+                    assert_eq!(length, !inverse & 0xffff);
+                    let start = reader.position / 8;
+                    out.extend(&data[start..start + length]);
+                    reader.position += length * 8;
+                },
+                1 => loop {
+                    let symbol = reader.literal();
+                    if symbol == 256 {
+                        break;
+                    }
+                    if symbol < 256 {
+                        out.push(symbol as u8);
+                        continue;
+                    }
+
+                    let index = symbol as usize - 257;
+                    let length = usize::from(LENGTH_BASE[index]) + reader.bits(u32::from(LENGTH_EXTRA[index])) as usize;
+                    let code = reader.code(5) as usize;
+                    let distance = usize::from(DISTANCE_BASE[code]) + reader.bits(u32::from(DISTANCE_EXTRA[code])) as usize;
+                    assert!(distance <= WINDOW_SIZE && distance <= out.len());
+
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                },
+                other => panic!("we never write block type {}", other)
+            }
+
+            if last {
+                return out;
+            }
+        }
+    }
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        // in bits
+        position: usize
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = (self.data[self.position / 8] >> (self.position % 8)) & 1;
+                value |= u32::from(bit) << i;
+                self.position += 1;
+            }
+            value
+        }
+
+        // huffman codes come highest bit first
+        fn code(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |code, _| (code << 1) | self.bits(1))
+        }
+
+        fn literal(&mut self) -> u32 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code;
+            }
+            let code = (code << 1) | self.bits(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + ((code << 1) | self.bits(1)) - 0x190
+            }
+        }
+
+        fn align(&mut self) {
+            self.position = self.position.div_ceil(8) * 8;
+        }
+    }
+
+    // xorshift, random enough to not compress and the same every run
+    fn noise(length: usize, mut seed: u64) -> Vec<u8> {
+        (0..length)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checksums_match_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        // long enough to need the modulus more than once
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(200);
+        let inputs: [&[u8]; 5] = [b"", b"a", b"abcabcabcabc", text.as_bytes(), &[b'x'; 100_000]];
+
+        for input in inputs {
+            assert_eq!(inflate(&deflate(input)), input);
+        }
+        assert!(deflate(text.as_bytes()).len() < text.len() / 10);
+    }
+
+    #[test]
+    fn falls_back_to_stored_blocks_over_64k() {
+        let input = noise(70_000, 1);
+        let compressed = deflate(&input);
+
+        // two stored blocks, five bytes of header each
+        assert_eq!(compressed.len(), input.len() + 10);
+        assert_eq!(compressed[0], 0, "first block should be stored and not final");
+        assert_eq!(inflate(&compressed), input);
+    }
+
+    #[test]
+    fn matches_reach_exactly_to_the_window_edge() {
+        let pattern = noise(50, 2);
+
+        for gap in [WINDOW_SIZE, WINDOW_SIZE + 1] {
+            let mut input = pattern.clone();
+            input.extend(noise(gap - pattern.len(), 3));
+            input.extend(&pattern);
+
+            let mut matcher = Matcher::new(&input);
+            for position in 0..gap {
+                matcher.insert(position);
+            }
+
+            let found = matcher.longest_match(gap);
+            if gap == WINDOW_SIZE {
+                assert_eq!(found, Some((pattern.len(), WINDOW_SIZE)));
+            } else {
+                assert!(found.is_none_or(|(_, distance)| distance <= WINDOW_SIZE));
+            }
+
+            assert_eq!(inflate(&compress(&input)), input);
+        }
+    }
+
+    #[test]
+    fn gzip_and_zlib_wrap_the_stream() {
+        let input = b"hello hello hello hello";
+
+        let gzipped = gzip(input);
+        assert_eq!(gzipped[..3], [0x1f, 0x8b, 8]);
+        assert_eq!(gzipped[gzipped.len() - 8..gzipped.len() - 4], crc32(input).to_le_bytes());
+        assert_eq!(gzipped[gzipped.len() - 4..], (input.len() as u32).to_le_bytes());
+        assert_eq!(inflate(&gzipped[10..gzipped.len() - 8]), input);
+
+        let zlibbed = zlib(input);
+        assert_eq!((u16::from(zlibbed[0]) << 8 | u16::from(zlibbed[1])) % 31, 0);
+        assert_eq!(zlibbed[zlibbed.len() - 4..], adler32(input).to_be_bytes());
+        assert_eq!(inflate(&zlibbed[2..zlibbed.len() - 4]), input);
+    }
+
+    #[test]
+    fn negotiates_by_q_value() {
+        assert_eq!(preferred("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(preferred("gzip;q=0.5, deflate"), Some(Encoding::Deflate));
+        assert_eq!(preferred("gzip;q=0, *"), Some(Encoding::Deflate));
+        assert_eq!(preferred("br, identity"), None);
+        assert_eq!(preferred("x-gzip"), Some(Encoding::Gzip));
+    }
+}
@@ -0,0 +1,331 @@
+//! Conditional requests, the `If-*` headers that let a client check whether
+//! its cached copy is still good instead of fetching the whole thing again.
+//!
+//! `Router::handle` checks them for GET and HEAD against the `ETag` and
+//! `Last-Modified` on the response the handler came up with. Anything else
+//! has to check before it changes anything, not after, so handlers for those
+//! call `check` themselves with the validators of what they're about to change.
+
+use std::time::SystemTime;
+
+use crate::date::parse_http_date;
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+use crate::status::StatusCode;
+
+// the headers a 304 keeps from the response it stands in for, everything else
+// describes a body the client isn't getting
+const NOT_MODIFIED_HEADERS: [&str; 6] = ["Cache-Control", "Content-Location", "Date", "ETag", "Expires", "Vary"];
+
+// swap a GET or HEAD response for a 304 or 412 if the request's preconditions
+// say so. only successful responses, an error page has nothing to compare against.
+pub(crate) fn evaluate(request: &Request, mut response: Response) -> Response {
+    let safe = matches!(request.method, Method::Get | Method::Head);
+    if !safe || !(200..300).contains(&response.status.code()) {
+        return response;
+    }
+
+    add_etag(&mut response);
+    let last_modified = response.headers.get("Last-Modified").and_then(parse_http_date);
+
+    match check(request, Some((response.headers.get("ETag"), last_modified))) {
+        Some(StatusCode::NotModified) => {
+            let mut not_modified = Response::new(StatusCode::NotModified);
+            for name in NOT_MODIFIED_HEADERS {
+                for value in response.headers.get_all(name) {
+                    not_modified.headers.append(name, value);
+                }
+            }
+            not_modified
+        },
+        Some(status) => Response::new(status),
+        None => response
+    }
+}
+
+/// Check the `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` headers on `request` against the resource it's for.
+///
+/// `current` is the `ETag` and `Last-Modified` time of the resource as it is
+/// now, or None if there's nothing there yet, e.g. for a PUT that would create
+/// it. Only `*` cares about the difference, it matches anything that exists.
+///
+/// Returns the status to answer with instead of going ahead, a 304 for a GET
+/// or HEAD that the client already has, or a 412 when a precondition failed.
+/// None means carry on as normal.
+pub fn check(request: &Request, current: Option<(Option<&str>, Option<SystemTime>)>) -> Option<StatusCode> {
+    let exists = current.is_some();
+    let (etag, last_modified) = current.unwrap_or_default();
+    let etag = etag.and_then(|etag| parse_etags(etag).into_iter().next());
+    let safe = matches!(request.method, Method::Get | Method::Head);
+
+    // If-Match beats If-Unmodified-Since, and needs a strong match
+    if let Some(if_match) = joined(request, "If-Match") {
+        let matched = if if_match.trim() == "*" {
+            exists
+        } else {
+            parse_etags(&if_match).iter().any(|tag| etag.as_ref().is_some_and(|etag| etag.strong_eq(tag)))
+        };
+        if !matched {
+            return Some(StatusCode::PreconditionFailed);
+        }
+    } else if let Some(since) = request.headers.get("If-Unmodified-Since").and_then(parse_http_date) {
+        // no Last-Modified means there's nothing to compare against, and the header is ignored
+        if last_modified.is_some_and(|modified| modified > since) {
+            return Some(StatusCode::PreconditionFailed);
+        }
+    }
+
+    // If-None-Match beats If-Modified-Since, and a weak match is good enough
+    let unchanged = if let Some(if_none_match) = joined(request, "If-None-Match") {
+        if if_none_match.trim() == "*" {
+            exists
+        } else {
+            parse_etags(&if_none_match).iter().any(|tag| etag.as_ref().is_some_and(|etag| etag.weak_eq(tag)))
+        }
+    } else if safe {
+        let since = request.headers.get("If-Modified-Since").and_then(parse_http_date);
+        matches!((last_modified, since), (Some(modified), Some(since)) if modified <= since)
+    } else {
+        false
+    };
+
+    if !unchanged {
+        None
+    } else if safe {
+        Some(StatusCode::NotModified)
+    } else {
+        // anything but a GET or HEAD would be doing something to the resource,
+        // which the client asked us not to if it still matched
+        Some(StatusCode::PreconditionFailed)
+    }
+}
+
+// give a body held in memory an ETag hashed from it, if the handler didn't set one
+pub(crate) fn add_etag(response: &mut Response) {
+    if !response.headers.contains("ETag") {
+        if let Body::Bytes(contents) = &response.body {
+            let etag = etag_for_bytes(contents);
+            response.headers.insert("ETag", &etag);
+        }
+    }
+}
+
+// a strong ETag for a body held in memory, a hash of its contents
+fn etag_for_bytes(contents: &[u8]) -> String {
+    // FNV-1a, nothing clever, it only has to change when the contents do
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in contents {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("\"{:x}-{:016x}\"", contents.len(), hash)
+}
+
+// every value for a header that can be sent more than once, joined up as if it was sent once
+fn joined(request: &Request, name: &str) -> Option<String> {
+    let values: Vec<&str> = request.headers.get_all(name).collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+pub(crate) struct EntityTag<'a> {
+    pub(crate) weak: bool,
+    // what's between the quotes
+    pub(crate) opaque: &'a str
+}
+
+impl EntityTag<'_> {
+    // both have to be strong and the same
+    pub(crate) fn strong_eq(&self, other: &EntityTag<'_>) -> bool {
+        !self.weak && !other.weak && self.opaque == other.opaque
+    }
+
+    // the same apart from being weak or not
+    fn weak_eq(&self, other: &EntityTag<'_>) -> bool {
+        self.opaque == other.opaque
+    }
+}
+
+// a comma separated list of entity tags, e.g. `"abc", W/"def"`. stops at the
+// first thing that isn't one, keeping whatever came before it.
+pub(crate) fn parse_etags(list: &str) -> Vec<EntityTag<'_>> {
+    let mut tags = Vec::new();
+    let mut rest = list;
+
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return tags;
+        }
+
+        let weak = match rest.strip_prefix("W/") {
+            Some(after) => {
+                rest = after;
+                true
+            },
+            None => false
+        };
+
+        // the quotes are part of the syntax, and the tag itself can't contain one
+        let quoted = match rest.strip_prefix('"') {
+            Some(quoted) => quoted,
+            None => return tags
+        };
+        let end = match quoted.find('"') {
+            Some(end) => end,
+            None => return tags
+        };
+
+        tags.push(EntityTag { weak, opaque: &quoted[..end] });
+        rest = &quoted[end + 1..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::date::http_date;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const ETAG: &str = "\"v1\"";
+
+    fn request(method: &str, headers: &str) -> Request {
+        let raw = format!("{} / HTTP/1.1\r\nHost: x\r\n{}\r\n", method, headers);
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn star_only_matches_something_that_exists() {
+        // create if absent
+        let put = request("PUT", "If-None-Match: *\r\n");
+        assert_eq!(check(&put, None), None);
+        assert_eq!(check(&put, Some((Some(ETAG), None))), Some(StatusCode::PreconditionFailed));
+        assert_eq!(check(&put, Some((None, None))), Some(StatusCode::PreconditionFailed));
+
+        // update only if present
+        let put = request("PUT", "If-Match: *\r\n");
+        assert_eq!(check(&put, None), Some(StatusCode::PreconditionFailed));
+        assert_eq!(check(&put, Some((None, None))), None);
+
+        let get = request("GET", "If-None-Match: *\r\n");
+        assert_eq!(check(&get, Some((Some(ETAG), None))), Some(StatusCode::NotModified));
+        assert_eq!(check(&get, None), None);
+    }
+
+    #[test]
+    fn if_match_needs_a_strong_match() {
+        assert_eq!(check(&request("PUT", "If-Match: \"v1\"\r\n"), Some((Some(ETAG), None))), None);
+        assert_eq!(check(&request("PUT", "If-Match: \"v0\", \"v1\"\r\n"), Some((Some(ETAG), None))), None);
+
+        let failed = Some(StatusCode::PreconditionFailed);
+        assert_eq!(check(&request("PUT", "If-Match: W/\"v1\"\r\n"), Some((Some(ETAG), None))), failed);
+        assert_eq!(check(&request("PUT", "If-Match: \"v1\"\r\n"), Some((Some("W/\"v1\""), None))), failed);
+        assert_eq!(check(&request("PUT", "If-Match: \"v2\"\r\n"), Some((Some(ETAG), None))), failed);
+        assert_eq!(check(&request("PUT", "If-Match: \"v1\"\r\n"), Some((None, None))), failed);
+    }
+
+    #[test]
+    fn if_none_match_takes_a_weak_match() {
+        let not_modified = Some(StatusCode::NotModified);
+        assert_eq!(check(&request("GET", "If-None-Match: W/\"v1\"\r\n"), Some((Some(ETAG), None))), not_modified);
+        assert_eq!(check(&request("HEAD", "If-None-Match: \"v1\"\r\n"), Some((Some("W/\"v1\""), None))), not_modified);
+        assert_eq!(check(&request("GET", "If-None-Match: \"v2\"\r\n"), Some((Some(ETAG), None))), None);
+
+        // anything but a GET or HEAD matching is a failed precondition, not a 304
+        let put = request("PUT", "If-None-Match: W/\"v1\"\r\n");
+        assert_eq!(check(&put, Some((Some(ETAG), None))), Some(StatusCode::PreconditionFailed));
+    }
+
+    #[test]
+    fn if_match_beats_if_unmodified_since() {
+        let earlier = http_date(at(1_000));
+        let current = Some((Some(ETAG), Some(at(2_000))));
+
+        // the date alone would fail, but If-Match is checked instead
+        let put = request("PUT", &format!("If-Match: \"v1\"\r\nIf-Unmodified-Since: {}\r\n", earlier));
+        assert_eq!(check(&put, current), None);
+
+        let put = request("PUT", &format!("If-Unmodified-Since: {}\r\n", earlier));
+        assert_eq!(check(&put, current), Some(StatusCode::PreconditionFailed));
+
+        let put = request("PUT", &format!("If-Unmodified-Since: {}\r\n", http_date(at(2_000))));
+        assert_eq!(check(&put, current), None);
+    }
+
+    #[test]
+    fn if_unmodified_since_is_ignored_without_a_last_modified() {
+        let put = request("PUT", "If-Unmodified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n");
+        assert_eq!(check(&put, Some((Some(ETAG), None))), None);
+
+        // and through evaluate, for a handler's body that only has the ETag we hash for it
+        let get = request("GET", "If-Unmodified-Since: Thu, 01 Jan 2099 00:00:00 GMT\r\n");
+        let response = evaluate(&get, Response::with_body(StatusCode::Ok, "hello"));
+        assert_eq!(response.status, StatusCode::Ok);
+    }
+
+    #[test]
+    fn if_none_match_beats_if_modified_since() {
+        let later = http_date(at(3_000));
+        let current = Some((Some(ETAG), Some(at(2_000))));
+
+        // the date alone would be a 304, but the ETag doesn't match
+        let get = request("GET", &format!("If-None-Match: \"v2\"\r\nIf-Modified-Since: {}\r\n", later));
+        assert_eq!(check(&get, current), None);
+
+        let get = request("GET", &format!("If-Modified-Since: {}\r\n", later));
+        assert_eq!(check(&get, current), Some(StatusCode::NotModified));
+
+        let get = request("GET", &format!("If-Modified-Since: {}\r\n", http_date(at(1_000))));
+        assert_eq!(check(&get, current), None);
+
+        // only GET and HEAD look at If-Modified-Since at all
+        let put = request("PUT", &format!("If-Modified-Since: {}\r\n", later));
+        assert_eq!(check(&put, current), None);
+    }
+
+    #[test]
+    fn evaluate_keeps_only_the_headers_a_304_needs() {
+        let get = request("GET", "If-None-Match: \"v1\"\r\n");
+        let response = Response::with_body(StatusCode::Ok, "hello")
+            .header("ETag", ETAG)
+            .header("Content-Type", "text/plain")
+            .header("Cache-Control", "no-cache");
+
+        let response = evaluate(&get, response);
+        assert_eq!(response.status, StatusCode::NotModified);
+        assert_eq!(response.headers.get("ETag"), Some(ETAG));
+        assert_eq!(response.headers.get("Cache-Control"), Some("no-cache"));
+        assert!(!response.headers.contains("Content-Type"));
+    }
+
+    #[test]
+    fn parses_etag_lists() {
+        let tags = parse_etags(" \"a\",W/\"b\" ,, \"\"");
+        let parsed: Vec<(bool, &str)> = tags.iter().map(|tag| (tag.weak, tag.opaque)).collect();
+        assert_eq!(parsed, [(false, "a"), (true, "b"), (false, "")]);
+    }
+
+    #[test]
+    fn stops_at_the_first_malformed_etag() {
+        let opaque = |list| parse_etags(list).iter().map(|tag| tag.opaque.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(opaque("\"a\", b, \"c\""), ["a"]);
+        assert_eq!(opaque("\"a\", \"unterminated"), ["a"]);
+        assert_eq!(opaque("W/"), Vec::<String>::new());
+        assert_eq!(opaque("w/\"a\""), Vec::<String>::new());
+        assert_eq!(opaque("*"), Vec::<String>::new());
+
+        // a malformed If-Match matches nothing
+        let put = request("PUT", "If-Match: v1\r\n");
+        assert_eq!(check(&put, Some((Some(ETAG), None))), Some(StatusCode::PreconditionFailed));
+    }
+}
@@ -0,0 +1,199 @@
+//! Dates the way HTTP headers like `Date` and `Last-Modified` want them,
+//! e.g. `Sun, 06 Nov 1994 08:49:37 GMT`. Always in GMT, no time zones to deal with.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Format `time` as an HTTP date. Anything before 1970 comes out as the epoch.
+pub fn http_date(time: SystemTime) -> String {
+    let secs = seconds_since_epoch(time);
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // the epoch was a thursday, which is why DAYS starts there
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+// anything before 1970 counts as the epoch
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+/// Read an HTTP date back in, as sent in headers like `If-Modified-Since`.
+///
+/// Takes the IMF-fixdate that `http_date` writes, plus the two older formats
+/// clients are still allowed to send, RFC 850 (`Sunday, 06-Nov-94 08:49:37 GMT`)
+/// and asctime (`Sun Nov  6 08:49:37 1994`). None if it's none of those, or
+/// outside 1970 to 9999.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+
+    // the day of the week is just along for the ride, nobody checks it's right
+    let (day, month, year, time) = match parts.as_slice() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse().ok()?, *time),
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            if year.len() != 2 || date.next().is_some() {
+                return None;
+            }
+
+            let this_year = civil_from_days(seconds_since_epoch(SystemTime::now()) / 86400).0;
+            (day, month, full_year(year.parse().ok()?, this_year), *time)
+        },
+        // Sun Nov  6 08:49:37 1994
+        [_, month, day, time, year] => (*day, *month, year.parse().ok()?, *time),
+        _ => return None
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() {
+        return None;
+    }
+
+    // 60 is a leap second. four digit years are all the formats have room for,
+    // and anything bigger could overflow working out the seconds.
+    if !(1970..=9999).contains(&year) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // no 31 Feb quietly turning into 3 March
+    if !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day)?
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// the year a two digit RFC 850 year means. the RFC says anything more than 50
+// years in the future is really the last year in the past ending in those digits,
+// so it's whichever year ending in them falls in the 100 years up to 50 from now.
+fn full_year(two_digits: u64, this_year: u64) -> u64 {
+    let year = this_year - this_year % 100 + two_digits;
+    if year > this_year + 50 {
+        year - 100
+    } else if year + 100 <= this_year + 50 {
+        year + 100
+    } else {
+        year
+    }
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+// days since 1970-01-01 to (year, month, day), using Howard Hinnant's algorithm
+// (http://howardhinnant.github.io/date_algorithms.html). works in 400 year eras
+// starting from march, so the leap day is always the last day of the "year".
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+// the other way round, (year, month, day) to days since 1970-01-01. None for
+// anything before 1970, or so far out it overflows.
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    let year = if month <= 2 { year.checked_sub(1)? } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era.checked_mul(146097)?.checked_add(day_of_era)?.checked_sub(719468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_all_three_formats() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784111777);
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(expected));
+    }
+
+    #[test]
+    fn round_trips_with_http_date() {
+        for secs in [0, 951782400, 1_800_000_000, 253402300799] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&http_date(time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn rejects_years_out_of_range_instead_of_overflowing() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 18446744073709551615 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 18446744073709551615"), None);
+        assert_eq!(parse_http_date("Sat, 01 Jan 10000 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+    }
+
+    #[test]
+    fn two_digit_years_are_never_more_than_50_years_ahead() {
+        assert_eq!(full_year(94, 2026), 1994);
+        assert_eq!(full_year(76, 2026), 2076);
+        assert_eq!(full_year(77, 2026), 1977);
+        assert_eq!(full_year(26, 2026), 2026);
+        assert_eq!(full_year(0, 2026), 2000);
+        // the pivot rolls along with the current year
+        assert_eq!(full_year(40, 2095), 2140);
+        assert_eq!(full_year(46, 2095), 2046);
+        assert_eq!(full_year(45, 2095), 2145);
+        assert_eq!(full_year(45, 1995), 2045);
+        assert_eq!(full_year(46, 1995), 1946);
+        assert_eq!(full_year(99, 2001), 1999);
+    }
+
+    #[test]
+    fn checks_the_day_against_the_month() {
+        assert_eq!(parse_http_date("Sat, 31 Feb 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 31 Apr 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 00 Apr 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Thu Feb 29 00:00:00 2024").map(http_date), Some("Thu, 29 Feb 2024 00:00:00 GMT".to_string()));
+        assert_eq!(parse_http_date("Sunday, 29-Feb-15 00:00:00 GMT"), None);
+
+        // 2000 was a leap year, 2100 won't be
+        assert!(parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT").is_some());
+        assert_eq!(parse_http_date("Mon, 29 Feb 2100 00:00:00 GMT"), None);
+        assert!(parse_http_date("Tue, 31 Dec 2024 23:59:59 GMT").is_some());
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+
+/// A list of HTTP header fields.
+///
+/// Field names are compared case-insensitively, the way HTTP wants them,
+/// but are stored with whatever casing they were given so they go back
+/// out on the wire looking the same.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    // a vec rather than a hashmap because a request has a handful of headers
+    // at most, order matters when writing them back out, and the same name
+    // is allowed to show up more than once.
+    entries: Vec<(String, String)>
+}
+
+impl Headers {
+    /// Create an empty header map.
+    pub fn new() -> Headers {
+        Headers { entries: Vec::new() }
+    }
+
+    /// Get the first value for `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get every value for `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set `name` to `value`, replacing any values already there.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Add another value for `name`, keeping the ones already there.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove every value for `name`, returning true if anything was removed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.entries.len() != before
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// writes the headers out the way they go on the wire, each one ending in \r\n.
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+        Ok(())
+    }
+}
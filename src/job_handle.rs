@@ -0,0 +1,84 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::panic_message;
+
+/// Why a job didn't hand back a result.
+pub enum JobError {
+    /// The job panicked. Holds whatever it panicked with.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// `join_timeout` ran out of time. The job may still finish later.
+    Timeout,
+    /// The job is never going to produce a result. Either it was thrown away
+    /// without running (e.g. the pool shut down first), or its result has
+    /// already been taken from this handle.
+    Lost
+}
+
+impl fmt::Debug for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(payload) => f.debug_tuple("Panicked").field(&panic_message(&**payload)).finish(),
+            JobError::Timeout => f.write_str("Timeout"),
+            JobError::Lost => f.write_str("Lost")
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(payload) => write!(f, "job panicked: {}", panic_message(&**payload)),
+            JobError::Timeout => write!(f, "timed out waiting for job"),
+            JobError::Lost => write!(f, "job result was lost")
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+/// A handle to the result of a job started with `ThreadPool::spawn`.
+///
+/// Dropping the handle doesn't stop the job, its result is just thrown away.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>
+}
+
+impl<T> JobHandle<T> {
+    pub(crate) fn new(receiver: mpsc::Receiver<thread::Result<T>>) -> JobHandle<T> {
+        JobHandle { receiver }
+    }
+
+    /// Block until the job finishes and return its result.
+    pub fn join(self) -> Result<T, JobError> {
+        match self.receiver.recv() {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(mpsc::RecvError) => Err(JobError::Lost)
+        }
+    }
+
+    /// Block until the job finishes or `timeout` passes, whichever comes first.
+    ///
+    /// On `JobError::Timeout` the handle can still be joined again later.
+    pub fn join_timeout(&self, timeout: Duration) -> Result<T, JobError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(JobError::Timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(JobError::Lost)
+        }
+    }
+
+    /// Get the result if the job has already finished, without blocking.
+    ///
+    /// Returns None while the job is still queued or running.
+    pub fn try_join(&self) -> Option<Result<T, JobError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result.map_err(JobError::Panicked)),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JobError::Lost))
+        }
+    }
+}
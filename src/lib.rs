@@ -1,18 +1,323 @@
+pub mod builder;
+pub mod cancel;
+pub mod chunked;
+mod compression;
+pub mod conditional;
+pub mod date;
+pub mod headers;
+pub mod job_handle;
+pub mod priority;
+mod queue;
+mod range;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod scheduler;
+pub mod scope;
+pub mod signal;
+pub mod static_files;
+pub mod stats;
+pub mod status;
+
+pub use builder::{BuildError, ThreadPoolBuilder};
+pub use cancel::CancellationToken;
+pub use chunked::ChunkedWriter;
+pub use headers::Headers;
+pub use job_handle::{JobError, JobHandle};
+pub use priority::Priority;
+pub use request::{Method, ParseError, Request, Version};
+pub use response::{Body, Response};
+pub use router::Router;
+pub use scheduler::TimerHandle;
+pub use scope::Scope;
+pub use static_files::StaticFiles;
+pub use status::StatusCode;
+pub use stats::{PoolStats, WorkerStats};
+
+use std::any::Any;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
 use std::thread;
 use std::sync::mpsc; // multiple producer, single consumer
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use queue::{JobQueue, Pop};
+use scheduler::Scheduler;
+use stats::WorkerCounters;
+
+// how often shutdown checks whether a worker has finished yet
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// how many pieces map and for_each split the work into for each worker
+const CHUNKS_PER_WORKER: usize = 4;
 
 
 // alias trait object containing a one use closure to Job
 type Job = Box<dyn FnOnce() + Send + 'static>;
-enum Message {
-    NewJob(Job),
-    Terminate
-}
+// gets told which worker it was and whatever the job panicked with
+type PanicHandler = Arc<dyn Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static>;
+// run on a worker thread as it starts up or stops, gets told the worker's id
+type ThreadHook = Arc<dyn Fn(usize) + Send + Sync + 'static>;
 pub struct ThreadPool {
-    workers: Vec<Worker>, // dont need closures to return anything
-    sender: mpsc::Sender<Message>
+    // kept here as well as in the workers so replacements can be hooked up to the same queue
+    shared: Arc<Shared>,
+    // only started the first time something is scheduled, most pools never need it
+    scheduler: OnceLock<Scheduler>
+}
+
+// everything the workers (and the scheduler) need a reference to
+struct Shared {
+    // behind a mutex so dead workers can be swapped out through a shared reference
+    workers: Mutex<Vec<Worker>>, // dont need closures to return anything
+    queue: JobQueue,
+    panic_handler: PanicHandler,
+    backlog: Backlog,
+    in_flight: InFlight,
+    // the range the number of workers is kept in, changed at runtime by `resize`
+    min_size: AtomicUsize,
+    max_size: AtomicUsize,
+    // how long a worker can sit without a job before it's reaped, if there are more than min_size
+    keep_alive: Duration,
+    // workers that are running (or about to be), and how many of them are waiting on a job
+    live: AtomicUsize,
+    idle: AtomicUsize,
+    // workers whose thread has exited but that haven't been cleaned up by the pool yet
+    stopped: AtomicUsize,
+    // jobs that have finished (including the ones that panicked), and the ones that panicked
+    finished: AtomicUsize,
+    panicked: AtomicUsize,
+    // jobs thrown away before they started, and ones whose token's deadline passed before they finished
+    cancelled: AtomicUsize,
+    timed_out: AtomicUsize,
+    // ids are never reused, so log lines for a reaped worker and its replacement can be told apart
+    next_id: AtomicUsize,
+    // the rest is how to set up a worker thread, kept around for respawning them
+    thread_name_prefix: String,
+    stack_size: Option<usize>,
+    on_thread_start: Option<ThreadHook>,
+    on_thread_stop: Option<ThreadHook>
+}
+
+// keeps count of jobs that have been sent but not picked up by a worker yet, so a
+// bounded pool can refuse (or make callers wait) once that gets to the capacity.
+// the queue itself is unbounded, this is what does the bounding.
+struct Backlog {
+    queued: AtomicUsize,
+    capacity: Option<usize>,
+    // the lock and condvar are only for callers waiting on a full queue. `waiters` lets
+    // release skip the lock when nobody is, so unbounded pools never touch it.
+    waiters: AtomicUsize,
+    lock: Mutex<()>,
+    // signalled whenever a worker takes a job off the queue and frees up a slot
+    space: Condvar
+}
+
+impl Backlog {
+    fn new(capacity: Option<usize>) -> Backlog {
+        Backlog {
+            queued: AtomicUsize::new(0),
+            capacity,
+            waiters: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            space: Condvar::new()
+        }
+    }
+
+    // take a slot if there is one, without waiting
+    fn try_reserve(&self) -> bool {
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                match self.capacity {
+                    Some(capacity) if queued >= capacity => None,
+                    _ => Some(queued + 1)
+                }
+            })
+            .is_ok()
+    }
+
+    // take a slot even if the queue is already full
+    fn force_reserve(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // take a slot, waiting for one to free up if the queue is full
+    fn reserve(&self) {
+        if self.try_reserve() {
+            return;
+        }
+
+        let mut lock = self.lock.lock().unwrap();
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+
+        while !self.try_reserve() {
+            lock = self.space.wait(lock).unwrap();
+        }
+
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn release(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        // same trick as the job queue: we've freed the slot before checking for waiters,
+        // and they register before their last try, so nobody gets missed.
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.space.notify_one();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+// keeps count of jobs that have been queued but haven't finished running yet, for wait_idle
+struct InFlight {
+    count: AtomicUsize,
+    // same as Backlog, the lock and condvar are only for whoever's waiting
+    waiters: AtomicUsize,
+    lock: Mutex<()>,
+    // signalled when the count gets back down to zero
+    idle: Condvar
+}
+
+impl InFlight {
+    fn new() -> InFlight {
+        InFlight { count: AtomicUsize::new(0), waiters: AtomicUsize::new(0), lock: Mutex::new(()), idle: Condvar::new() }
+    }
+
+    fn start(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finish(&self) {
+        if self.count.fetch_sub(1, Ordering::SeqCst) == 1 && self.waiters.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.idle.notify_all();
+        }
+    }
+
+    // true once the count is zero, or false if the deadline came first
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        if self.count.load(Ordering::SeqCst) == 0 {
+            return true;
+        }
+
+        let mut lock = self.lock.lock().unwrap();
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+
+        let idle = loop {
+            if self.count.load(Ordering::SeqCst) == 0 {
+                break true;
+            }
+
+            match deadline {
+                None => lock = self.idle.wait(lock).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break false;
+                    }
+                    lock = self.idle.wait_timeout(lock, deadline - now).unwrap().0;
+                }
+            }
+        };
+
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        idle
+    }
+}
+
+impl Shared {
+    // queue a job that already has its slot in the backlog, and make sure there's a worker to take it
+    fn submit(self: &Arc<Self>, job: Job, priority: Priority) {
+        self.in_flight.start();
+        self.queue.push(job, priority); // put that job on the queue for a worker to pick up
+
+        if self.needs_maintenance() {
+            self.maintain_workers();
+        }
+    }
+
+    // cheap check for whether maintain_workers has anything to do, so execute
+    // doesn't have to take the workers lock every time.
+    fn needs_maintenance(&self) -> bool {
+        let live = self.live.load(Ordering::SeqCst);
+
+        self.stopped.load(Ordering::SeqCst) > 0
+            || live < self.min_size.load(Ordering::SeqCst)
+            || (self.backlog.len() > self.idle.load(Ordering::SeqCst) && live < self.max_size.load(Ordering::SeqCst))
+    }
+
+    // clean up after workers that have stopped, then spawn more if there are
+    // fewer than the min or jobs are backing up with nobody free to take them.
+    fn maintain_workers(self: &Arc<Self>) {
+        let mut workers = self.workers.lock().unwrap();
+
+        // a worker stops on purpose when it's reaped or retired. job panics are caught,
+        // so otherwise it only dies if something outside of a job panics, like the
+        // panic handler itself. either way it's already off the live count.
+        workers.retain_mut(|worker| {
+            let stopped = worker.thread.as_ref().is_some_and(|thread| thread.is_finished());
+            if !stopped {
+                return true;
+            }
+
+            if let Some(thread) = worker.thread.take() {
+                if let Err(payload) = thread.join() {
+                    println!("Worker {} died: {}", worker.id, panic_message(&*payload));
+                }
+            }
+            self.stopped.fetch_sub(1, Ordering::SeqCst);
+            false
+        });
+
+        let mut wanted = self.min_size.load(Ordering::SeqCst);
+
+        let backed_up = self.backlog.len() > self.idle.load(Ordering::SeqCst);
+        if backed_up {
+            wanted = wanted.max(self.live.load(Ordering::SeqCst) + 1);
+        }
+
+        // topping up to the min replaces any that died, so the pool never drops below it
+        let wanted = wanted.min(self.max_size.load(Ordering::SeqCst));
+
+        while self.live.load(Ordering::SeqCst) < wanted {
+            // if the os won't give us a thread right now, carry on without and try again next time
+            if let Err(err) = self.spawn_worker(&mut workers) {
+                println!("Couldn't spawn a worker: {}", err);
+                return;
+            }
+        }
+    }
+
+    fn spawn_worker(self: &Arc<Self>, workers: &mut Vec<Worker>) -> io::Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        // counted before it starts, so nothing else decides to spawn one too in the meantime
+        self.live.fetch_add(1, Ordering::SeqCst);
+
+        match Worker::new(id, Arc::clone(self)) {
+            Ok(worker) => {
+                workers.push(worker);
+                Ok(())
+            },
+            Err(err) => {
+                self.live.fetch_sub(1, Ordering::SeqCst);
+                Err(err)
+            }
+        }
+    }
+
+    // take one worker off the live count, but only if that leaves more than `limit`.
+    // whichever worker gets true back has to exit.
+    fn retire_above(&self, limit: usize) -> bool {
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| if live > limit { Some(live - 1) } else { None })
+            .is_ok()
+    }
 }
 
 impl ThreadPool {
@@ -20,86 +325,602 @@ impl ThreadPool {
     ///
     /// The size is the number of threads in the pool.
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero, or if the threads can't be spawned.
+    /// Use `ThreadPool::builder` to get an error back instead.
     pub fn new(size: usize) -> ThreadPool {
-        // just assert and panic, dont bother with results cause there should be
-        // no handling for 0 threads, the software just wont work.
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        // just panic, there should be no handling for 0 threads, the software just wont work.
+        ThreadPool::builder().size(size).build().unwrap()
+    }
 
-        // is a little bit more efficient to pre-allocate the memory here with #with_capacity
-        let mut workers = Vec::with_capacity(size);
+    /// Create a new ThreadPool that calls `handler` whenever a job panics.
+    ///
+    /// The handler gets the id of the worker and the panic payload. The worker
+    /// carries on with the next job afterwards, so a panicking job never
+    /// costs the pool a thread.
+    ///
+    /// Will panic if the size is zero.
+    pub fn with_panic_handler<F>(size: usize, handler: F) -> ThreadPool
+    where
+        F: Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static
+    {
+        ThreadPool::builder().size(size).panic_handler(handler).build().unwrap()
+    }
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
+    /// Create a new ThreadPool that holds at most `capacity` jobs waiting for a worker.
+    ///
+    /// Once that many are queued, `execute` blocks until a worker frees up a
+    /// slot and `try_execute` hands the job straight back.
+    ///
+    /// Will panic if the size or capacity is zero.
+    pub fn bounded(size: usize, capacity: usize) -> ThreadPool {
+        ThreadPool::builder().size(size).queue_capacity(capacity).build().unwrap()
+    }
 
-        ThreadPool { workers, sender }
+    /// Start setting up a pool with named threads, lifecycle hooks and the like.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
     }
 
     // take a closure arg thats called once, remember closures are defined as trait like this
     // Send to transfer closure from one thread to another and 'static
     // because we dont know how long the thread will take to execute
+    // on a bounded pool this waits for room in the queue first.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static
     {
-        let job = Box::new(f); // create a Job instance (our alias)
+        self.execute_with_priority(Priority::Normal, f);
+    }
 
-        self.sender.send(Message::NewJob(job)).unwrap(); // send that job down the channel
+    /// Like `execute`, but workers pick `f` up ahead of any lower priority jobs
+    /// that are waiting (and behind any higher priority ones).
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static
+    {
+        self.shared.backlog.reserve();
+        self.shared.submit(Box::new(f), priority); // create a Job instance (our alias)
     }
-}
 
-// call join on all the worker threads when shutting down / dropping a threadpool
-// note to self: join takes ownership so cant be working with references.
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+    /// Queue `f` if there's room, otherwise hand it straight back.
+    ///
+    /// Only a pool made with `bounded` can be full, on any other pool this
+    /// always succeeds.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static
+    {
+        self.try_execute_with_priority(Priority::Normal, f)
+    }
+
+    /// `try_execute` with a priority, see `execute_with_priority`.
+    pub fn try_execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static
+    {
+        if !self.shared.backlog.try_reserve() {
+            return Err(f);
+        }
+
+        self.shared.submit(Box::new(f), priority);
+        Ok(())
+    }
+
+    /// Run `f` on the pool, handing it a token that can be used to call it off.
+    ///
+    /// Returns another copy of the token, see `execute_with_token`.
+    pub fn execute_cancellable<F>(&self, f: F) -> CancellationToken
+    where
+        F: FnOnce(&CancellationToken) + Send + 'static
+    {
+        let token = CancellationToken::new();
+        self.execute_with_token(token.clone(), f);
+        token
+    }
+
+    /// Run `f` on the pool with `token`, e.g. one shared by every job for a
+    /// connection so they can all be called off when the client goes away.
+    ///
+    /// If the token is cancelled (or its deadline passes) before a worker gets
+    /// to the job, it's thrown away without running. Once it's running `f` has
+    /// to check the token itself. Either way a job whose deadline passes before
+    /// it's done is logged and counted in `PoolStats::timed_out`.
+    pub fn execute_with_token<F>(&self, token: CancellationToken, f: F)
+    where
+        F: FnOnce(&CancellationToken) + Send + 'static
+    {
+        let shared = Arc::clone(&self.shared);
+
+        self.execute(move || {
+            if token.is_cancelled() {
+                shared.cancelled.fetch_add(1, Ordering::SeqCst);
+
+                if token.is_timed_out() {
+                    shared.timed_out.fetch_add(1, Ordering::SeqCst);
+                    println!("Job timed out before it started, dropping it.");
+                }
+                return;
+            }
+
+            f(&token);
+
+            if let Some(deadline) = token.deadline() {
+                let now = Instant::now();
+                if now > deadline {
+                    shared.timed_out.fetch_add(1, Ordering::SeqCst);
+                    println!("Job overran its deadline by {:?}.", now - deadline);
+                }
+            }
+        });
+    }
+
+    /// Run `f` on the pool and get a handle to whatever it returns.
+    ///
+    /// If `f` panics, the panic is handed to whoever joins the handle as
+    /// `JobError::Panicked` instead of going to the pool's panic handler.
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static
+    {
+        self.spawn_with_priority(Priority::Normal, f)
+    }
+
+    /// `spawn` with a priority, see `execute_with_priority`.
+    pub fn spawn_with_priority<F, T>(&self, priority: Priority, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
 
-        for _ in &self.workers {
-            // they stop their infinite loops if they receive this.
-            // otherwise the loop would continue and join would wait for it to finish (it never would)
-            self.sender.send(Message::Terminate).unwrap();
+        let shared = Arc::clone(&self.shared);
+
+        self.execute_with_priority(priority, move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // the worker never sees this panic, so count it here instead
+            if result.is_err() {
+                shared.panicked.fetch_add(1, Ordering::SeqCst);
+            }
+            // the handle might have been dropped already, which just means nobody wants the result
+            let _ = sender.send(result);
+        });
+
+        JobHandle::new(receiver)
+    }
+
+    /// Run `f` with a `Scope` that can start jobs borrowing from the caller's
+    /// stack, in the spirit of `std::thread::scope`. Waits for all of them to
+    /// finish before returning whatever `f` returned.
+    ///
+    /// If `f` or any of the jobs panicked, the panic is passed on once they're all done.
+    ///
+    /// Don't call this from a job running on the same pool: if every worker is
+    /// stuck in here waiting, there's nobody left to run the scoped jobs.
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T
+    {
+        let scope = Scope::new(self);
+
+        // even if f panics part way, jobs it already started can still be using what they borrowed
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        let job_panic = scope.wait();
+
+        let value = match result {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload)
+        };
+        if let Some(payload) = job_panic {
+            panic::resume_unwind(payload);
+        }
+        value
+    }
+
+    /// Call `f` on every item across the pool's workers and collect the results,
+    /// in the same order as the items.
+    ///
+    /// The items are split into a few chunks per worker rather than a job each,
+    /// so the queue isn't flooded when there are lots of small ones. If `f`
+    /// panics on any of them, the panic is passed on once the rest are done.
+    /// Same as `scope`, don't call this from a job on the same pool.
+    pub fn map<I, F, T>(&self, items: I, f: F) -> Vec<T>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> T + Sync,
+        T: Send
+    {
+        let chunks = self.chunk(items);
+        let mut results: Vec<Vec<T>> = chunks.iter().map(|_| Vec::new()).collect();
+        let f = &f;
+
+        self.scope(|scope| {
+            for (chunk, results) in chunks.into_iter().zip(results.iter_mut()) {
+                scope.execute(move || results.extend(chunk.into_iter().map(f)));
+            }
+        });
+
+        results.into_iter().flatten().collect()
+    }
+
+    /// Call `f` on every item across the pool's workers, and wait for them all.
+    ///
+    /// Split up and run the same way as `map`.
+    pub fn for_each<I, F>(&self, items: I, f: F)
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) + Sync
+    {
+        let chunks = self.chunk(items);
+        let f = &f;
+
+        self.scope(|scope| {
+            for chunk in chunks {
+                scope.execute(move || chunk.into_iter().for_each(f));
+            }
+        });
+    }
+
+    // split items up for map and for_each. a few chunks per worker, so one
+    // chunk that's slower than the rest doesn't leave everyone else waiting.
+    fn chunk<I: IntoIterator>(&self, items: I) -> Vec<Vec<I::Item>> {
+        let mut items: Vec<I::Item> = items.into_iter().collect();
+        if items.is_empty() {
+            return Vec::new();
         }
 
+        let chunks = (self.size().max(1) * CHUNKS_PER_WORKER).min(items.len());
+        let chunk_size = items.len().div_ceil(chunks);
+
+        // split off the end so each item only gets moved once, then put them back in order
+        let mut split = Vec::with_capacity(chunks);
+        while !items.is_empty() {
+            let last_chunk_start = (items.len() - 1) / chunk_size * chunk_size;
+            split.push(items.split_off(last_chunk_start));
+        }
+        split.reverse();
+        split
+    }
+
+    /// Run `f` on the pool once `delay` has passed.
+    ///
+    /// It's queued like any other job when it comes due, so on a busy pool it can
+    /// start a bit later than that. Timers that haven't fired yet are dropped
+    /// when the pool shuts down.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static
+    {
+        self.scheduler().once(delay, Box::new(f))
+    }
+
+    /// Run `f` on the pool every `interval`, starting one interval from now,
+    /// until the handle is cancelled or the pool shuts down.
+    ///
+    /// If a run is still going when the next one comes due, that one is skipped
+    /// rather than running two at once.
+    ///
+    /// Will panic if the interval is zero.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static
+    {
+        assert!(!interval.is_zero(), "interval must be greater than zero");
+
+        self.scheduler().every(interval, Box::new(f))
+    }
+
+    fn scheduler(&self) -> &Scheduler {
+        // if the os won't give us a thread for this there's not much else to do, same as `new`
+        self.scheduler
+            .get_or_init(|| Scheduler::start(Arc::clone(&self.shared)).expect("couldn't spawn the scheduler thread"))
+    }
+
+    /// Change the pool to have exactly `size` workers from now on.
+    ///
+    /// Growing spawns the new workers straight away. Shrinking lets the extra
+    /// workers finish what's already queued before they stop.
+    ///
+    /// Will panic if the size is zero.
+    pub fn resize(&self, size: usize) {
+        self.resize_bounds(size, size);
+    }
+
+    /// Let the number of workers move between `min` and `max` from now on.
+    ///
+    /// The pool grows towards `max` while jobs are queuing up faster than
+    /// workers can take them, and workers idle for longer than the keep-alive
+    /// are reaped until it's back down to `min`.
+    ///
+    /// Will panic if `max` is zero or less than `min`.
+    pub fn resize_bounds(&self, min: usize, max: usize) {
+        assert!(max > 0 && min <= max);
+
+        self.shared.min_size.store(min, Ordering::SeqCst);
+        self.shared.max_size.store(max, Ordering::SeqCst);
+
+        // any workers over the new max stop as soon as they notice. busy ones check
+        // between jobs, idle ones need waking up to check.
+        self.shared.queue.wake_all();
+
+        self.shared.maintain_workers();
+    }
+
+    /// Block until nothing is queued and no worker is running a job, without
+    /// shutting anything down. The pool can carry on being used afterwards.
+    ///
+    /// Jobs scheduled with `execute_after` or `execute_every` only count once
+    /// they come due. Never returns if called from a job on the same pool,
+    /// since that job is still running.
+    pub fn wait_idle(&self) {
+        self.shared.in_flight.wait(None);
+    }
+
+    /// `wait_idle`, giving up after `timeout`. Returns false if the pool was still busy by then.
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        self.shared.in_flight.wait(Some(Instant::now() + timeout))
+    }
+
+    /// How many worker threads the pool has right now.
+    pub fn size(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Take a snapshot of how busy the pool is.
+    pub fn stats(&self) -> PoolStats {
+        let per_worker: Vec<WorkerStats> = self
+            .shared
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            // skip ones that have stopped but haven't been cleaned up yet
+            .filter(|worker| worker.thread.as_ref().is_some_and(|thread| !thread.is_finished()))
+            .map(|worker| worker.counters.snapshot(worker.id))
+            .collect();
+
+        let finished = self.shared.finished.load(Ordering::SeqCst);
+        let panicked = self.shared.panicked.load(Ordering::SeqCst);
+        let cancelled = self.shared.cancelled.load(Ordering::SeqCst);
+
+        PoolStats {
+            queued: self.shared.backlog.len(),
+            workers: per_worker.len(),
+            active: per_worker.iter().filter(|worker| worker.busy).count(),
+            completed: finished.saturating_sub(panicked + cancelled),
+            panicked,
+            cancelled,
+            timed_out: self.shared.timed_out.load(Ordering::SeqCst),
+            per_worker
+        }
+    }
+
+    /// Shut the pool down, giving jobs that are already queued or running up to
+    /// `timeout` to finish.
+    ///
+    /// Returns false if some workers were still busy when time ran out. Those
+    /// are left running in the background rather than waited on.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        self.terminate(Some(Instant::now() + timeout))
+    }
+
+    // tell every worker still running to stop once it's through the queue, then join them.
+    // with a deadline, any worker that hasn't finished by then is abandoned.
+    fn terminate(&mut self, deadline: Option<Instant>) -> bool {
+        // no more timers going off, they'd only be queueing jobs nobody's going to run
+        if let Some(scheduler) = self.scheduler.get_mut() {
+            scheduler.stop();
+        }
+
+        // nothing else can be adding workers now, we've got &mut self and the scheduler's gone
+        let mut workers = self.shared.workers.lock().unwrap();
+
+        // already shut down
+        if workers.iter().all(|worker| worker.thread.is_none()) {
+            return true;
+        }
+
+        println!("Telling all workers to terminate.");
+
+        // they stop their infinite loops once the queue is closed and empty.
+        // otherwise the loop would continue and join would wait for it to finish (it never would)
+        self.shared.queue.close();
+
         println!("Shutting down all workers.");
 
-        // call join in a second loop to prevent deadlocks, aka once every worker
-        // has already received the terminate message.
-        for worker in &mut self.workers {
-            println!("Shutting down worker: {}", worker.id);
+        let mut all_finished = true;
+
+        // every worker has already been told to stop by now, so none of these joins can
+        // end up waiting on a worker that's still waiting for a job.
+        for worker in workers.iter_mut() {
             // use take to take ownership of Option<thread::JoinHandle<()>> and change variant to None.
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            let thread = match worker.thread.take() {
+                Some(thread) => thread,
+                None => continue
+            };
+
+            println!("Shutting down worker: {}", worker.id);
+
+            // JoinHandle has no join with a timeout, so poll until it's done or we run out of time
+            if let Some(deadline) = deadline {
+                while !thread.is_finished() && Instant::now() < deadline {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                }
+
+                if !thread.is_finished() {
+                    println!("Worker {} didn't finish in time, leaving it behind.", worker.id);
+                    all_finished = false;
+                    continue;
+                }
+            }
+
+            // only errors if the worker itself panicked, which isn't worth panicking again over mid shutdown
+            if let Err(payload) = thread.join() {
+                println!("Worker {} had died: {}", worker.id, panic_message(&*payload));
             }
         }
+
+        all_finished
+    }
+}
+
+// call join on all the worker threads when shutting down / dropping a threadpool
+// note to self: join takes ownership so cant be working with references.
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // no deadline here, wait as long as it takes.
+        // does nothing if `shutdown` already got to them first.
+        self.terminate(None);
     }
 }
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>
+    thread: Option<thread::JoinHandle<()>>,
+    // shared with the thread, which keeps them up to date
+    counters: Arc<WorkerCounters>
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        // loop forever constantly ask the receiving end of the channel for a job and running it when it gets one
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
-                    job();
-                },
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate. Terminating.", id);
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let mut builder = thread::Builder::new().name(format!("{}{}", shared.thread_name_prefix, id));
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let counters = Arc::new(WorkerCounters::default());
+        let thread_counters = Arc::clone(&counters);
+
+        let thread = builder.spawn(move || {
+            let counters = thread_counters;
+
+            if let Some(on_start) = &shared.on_thread_start {
+                on_start(id);
+            }
+            // a guard rather than a call after the loop, so the stop hook still
+            // runs if the thread is on its way out because of a panic.
+            let mut stop = StopGuard { id, shared: Arc::clone(&shared), counted: true };
+
+            // loop forever constantly ask the queue for a job and running it when it gets one
+            loop {
+                // a busy worker has to notice the pool shrinking between jobs
+                if shared.retire_above(shared.max_size.load(Ordering::SeqCst)) {
+                    println!("Worker {} retired, pool is shrinking.", id);
+                    stop.counted = false;
                     break;
                 }
+
+                shared.idle.fetch_add(1, Ordering::SeqCst);
+                // wake up every so often even with nothing to do, to see if we should be reaped
+                let pop = shared.queue.pop(id, shared.keep_alive);
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+
+                match pop {
+                    Pop::Job(job) => {
+                        // it's off the queue now, so make room for another
+                        shared.backlog.release();
+                        // dropped at the end of this arm whatever happens, even if the panic handler panics
+                        let _finished = FinishGuard { in_flight: &shared.in_flight };
+
+                        counters.start_job();
+                        let started = Instant::now();
+
+                        // catch the panic here rather than letting it unwind the whole thread,
+                        // otherwise one bad request would leave the pool a worker down for good.
+                        // AssertUnwindSafe is fine since the job is gone after this either way.
+                        let result = panic::catch_unwind(AssertUnwindSafe(job));
+
+                        counters.finish_job(started.elapsed());
+                        shared.finished.fetch_add(1, Ordering::SeqCst);
+
+                        if let Err(payload) = result {
+                            shared.panicked.fetch_add(1, Ordering::SeqCst);
+                            (shared.panic_handler)(id, &*payload);
+                        }
+                    },
+                    Pop::Closed => {
+                        println!("Worker {} was told to terminate. Terminating.", id);
+                        break;
+                    },
+                    Pop::TimedOut => {
+                        if shared.retire_above(shared.min_size.load(Ordering::SeqCst)) {
+                            println!("Worker {} was idle too long. Reaping.", id);
+                            stop.counted = false;
+                            break;
+                        }
+                    },
+                    // checked at the top of the loop
+                    Pop::Woken => {}
+                }
             }
+        })?;
 
-        });
-        Worker { id, thread: Some(thread) }
+        Ok(Worker { id, thread: Some(thread), counters })
+    }
+}
+
+// counts a job as no longer in flight when dropped, so wait_idle doesn't
+// wait forever on a job whose panic handler took the worker down with it
+struct FinishGuard<'a> {
+    in_flight: &'a InFlight
+}
+
+impl Drop for FinishGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.finish();
+    }
+}
+
+// runs the on_thread_stop hook when dropped at the end of a worker thread,
+// and takes the worker off the live count if it hasn't already done that itself.
+struct StopGuard {
+    id: usize,
+    shared: Arc<Shared>,
+    counted: bool
+}
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        if self.counted {
+            self.shared.live.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.shared.stopped.fetch_add(1, Ordering::SeqCst);
+        if let Some(on_stop) = &self.shared.on_thread_stop {
+            on_stop(self.id);
+        }
+    }
+}
+
+// used when nobody gives the pool a panic handler of their own
+fn default_panic_handler() -> PanicHandler {
+    Arc::new(|id, payload| {
+        println!("Worker {} panicked while running a job: {}", id, panic_message(payload));
+    })
+}
+
+/// Get the message out of a panic payload, for the usual case where it was
+/// `panic!` with a string. Anything else gets a placeholder.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_idle_survives_a_panicking_panic_handler() {
+        let pool = ThreadPool::with_panic_handler(2, |_, _| panic!("the handler panicked too"));
+
+        pool.execute(|| panic!("the job panicked"));
+
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
     }
 }
@@ -1,54 +1,247 @@
-use std::fs;
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use rust_webserver::ThreadPool;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use rust_webserver::{signal, static_files};
+use rust_webserver::{Method, ParseError, Request, Response, Router, StaticFiles, StatusCode, ThreadPool, Version};
+
+// biggest request body we'll accept, anything over gets a 413
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+// where static files are served from
+const DOCUMENT_ROOT: &str = "views";
+// how long a kept alive connection can sit there without sending anything before we hang up
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+// stop one client hogging a worker forever by reusing the same connection
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+// the pool never goes below MIN_WORKERS threads, and grows up to MAX_WORKERS when busy
+const MIN_WORKERS: usize = 2;
+const MAX_WORKERS: usize = 16;
+// how long an extra worker can sit idle before it's reaped
+const WORKER_KEEP_ALIVE: Duration = Duration::from_secs(30);
+// connections allowed to wait for a free worker before we start answering 503
+const MAX_QUEUED_CONNECTIONS: usize = 64;
+// how long a turned away client is told to wait before trying again
+const RETRY_AFTER_SECONDS: &str = "1";
+// the most time we'll spend on a turned away client, writing the 503 and then
+// waiting for it to finish sending its request before closing on it
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+// most of a turned away client's request we'll read and throw away
+const MAX_LINGER_BYTES: usize = 64 * 1024;
+// turned away clients we'll wait on at once, any more just get closed on straight away
+const MAX_LINGERING: usize = 64;
+// how long in-flight requests get to finish once we've been told to shut down
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how many linger threads are running right now
+static LINGERING: AtomicUsize = AtomicUsize::new(0);
 
 fn main() {
     // panic if cant bind
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
 
-    let pool = ThreadPool::new(5);
+    // grows with traffic and shrinks back when it's quiet.
+    // bounded so a flood of connections gets turned away with a 503 instead of piling up in memory
+    let pool = ThreadPool::builder()
+        .min_size(MIN_WORKERS)
+        .max_size(MAX_WORKERS)
+        .keep_alive(WORKER_KEEP_ALIVE)
+        .queue_capacity(MAX_QUEUED_CONNECTIONS)
+        .thread_name("http-worker-")
+        .build()
+        .unwrap();
+
+    // every worker needs to get at the routes, and they never change once we're
+    // up and running, so just share the one router behind an Arc.
+    let router = Arc::new(routes());
+
+    // ctrl-c / SIGTERM now just flip a flag, we check it below and shut down properly
+    signal::listen_for_shutdown();
+
+    // accept blocks until the next connection, so it would never notice the signal on
+    // its own. a watcher thread waits for it instead, then connects to us to wake accept up.
+    let address = listener.local_addr().unwrap();
+    thread::Builder::new()
+        .name("shutdown-watcher".to_string())
+        .spawn(move || {
+            signal::wait_for_shutdown();
+            let _ = TcpStream::connect(address);
+        })
+        .unwrap();
 
     // a single stream represents an open connection between the client and server.
-    // a connection = full request and response process.
+    // a connection can carry a whole series of requests and responses if the client keeps it alive,
     // so process each connection in turn and produce a series of streams for us to handle
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                println!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+
+        // most likely the watcher waking us up, but either way nothing new gets served now
+        if signal::shutdown_requested() {
+            break;
+        }
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+        let router = Arc::clone(&router);
+        // shared so we've still got the stream to answer on if the pool hands the job back
+        let stream = Arc::new(stream);
+        let connection = Arc::clone(&stream);
+
+        if pool.try_execute(move || handle_connection(&connection, &router)).is_err() {
+            println!("Too busy, turning connection away.");
+            service_unavailable(stream);
+        }
+    }
+
+    println!("Shutdown requested, no longer accepting connections.");
+    // stop the os queueing up connections we're never going to accept
+    drop(listener);
+
+    if !pool.shutdown(DRAIN_TIMEOUT) {
+        println!("Gave up waiting on in-flight requests after {:?}.", DRAIN_TIMEOUT);
     }
 }
 
-// the b transforms into a byte string so we can compare it with the buffer
-const GET: &[u8; 16] = b"GET / HTTP/1.1\r\n";
-
-// stream has to be mutable because internal state for it might change
-fn handle_connection(mut stream: TcpStream) {
-    // 1024 bytes in size, big enough to hold a basic request.
-    // bytes are pretty much universally used as chars
-    let mut buffer = [0; 1024];
-    // read bytes from stream and put them into buffer
-    stream.read(&mut buffer).unwrap();
-
-
-    let (status_line, filename) = if buffer.starts_with(GET) {
-        ("HTTP/1.1 200 OK", "views/index.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "views/404.html")
-    };
-
-    let contents = fs::read_to_string(filename).unwrap();
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
-
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
-    // produce a string from &[u8]
-    println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
+fn routes() -> Router {
+    let mut router = Router::new();
+    let files = StaticFiles::new(DOCUMENT_ROOT).precompressed(true);
+
+    // anything under the document root can be fetched, e.g. `/` gets views/index.html
+    router.get("/*path", move |request| {
+        files
+            .serve_request(request, request.param("path").unwrap_or(""))
+            .unwrap_or_else(not_found)
+    });
+    router.not_found(|_| not_found());
+
+    router
+}
+
+fn not_found() -> Response {
+    view(StatusCode::NotFound, "views/404.html")
+}
+
+// stream a page out of views/, falling back to a bare 500 if it's gone missing
+fn view(status: StatusCode, filename: &str) -> Response {
+    match File::open(filename) {
+        Ok(file) => {
+            Response::with_file(status, file).header("Content-Type", static_files::content_type(Path::new(filename)))
+        },
+        Err(err) => {
+            println!("Couldn't open {}: {}", filename, err);
+            Response::new(StatusCode::InternalServerError)
+        }
+    }
+}
+
+// tell the client to come back later. done straight from the accept loop, so
+// it never waits on a worker and doesn't bother parsing the request.
+fn service_unavailable(stream: Arc<TcpStream>) {
+    let response = Response::new(StatusCode::ServiceUnavailable).header("Retry-After", RETRY_AFTER_SECONDS);
+
+    // the 503 is tiny and nearly always fits in the socket buffer, but a client
+    // that isn't reading mustn't be able to hold up accept
+    if stream.set_write_timeout(Some(LINGER_TIMEOUT)).is_err()
+        || response.write_to(&mut &*stream, Version::Http11, false).is_err()
+    {
+        return;
+    }
+
+    // closing with the request still unread makes the kernel send a reset, which can
+    // get to the client before it's read our 503 and throw it away. so say we're done
+    // writing, then drain whatever it sends on a thread of its own, well away from accept.
+    let _ = stream.shutdown(Shutdown::Write);
+
+    if LINGERING.fetch_add(1, Ordering::SeqCst) >= MAX_LINGERING {
+        // already waiting on plenty of them, this one will just have to take its chances
+        LINGERING.fetch_sub(1, Ordering::SeqCst);
+        return;
+    }
+
+    let spawned = thread::Builder::new().name("linger".to_string()).spawn(move || {
+        linger(&stream);
+        LINGERING.fetch_sub(1, Ordering::SeqCst);
+    });
+    if spawned.is_err() {
+        LINGERING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// read and drop whatever a turned away client sends until it hangs up, or
+// LINGER_TIMEOUT is up. the timeout is for the whole drain, not each read, so
+// trickling in a byte at a time doesn't keep it going.
+fn linger(mut stream: &TcpStream) {
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut buffer = [0; 4096];
+    let mut drained = 0;
+
+    while drained < MAX_LINGER_BYTES {
+        // a zero read timeout would mean wait forever, so stop before it gets there
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => drained += read
+        }
+    }
+}
+
+fn handle_connection(stream: &TcpStream, router: &Router) {
+    // a read that blocks longer than this errors out, which is how idle connections get closed
+    if stream.set_read_timeout(Some(IDLE_TIMEOUT)).is_err() {
+        return;
+    }
+
+    // buffer the reads so the parser can pull the request in a line at a time
+    // instead of us guessing how big a buffer it needs up front.
+    // the same reader is kept for the whole connection, so any pipelined requests
+    // that arrived in the same read are still sitting in its buffer for the next loop.
+    let mut reader = BufReader::new(stream);
+    // &TcpStream implements Write too, so we can write while the reader holds its own reference
+    let mut writer = stream;
+
+    for served in 1..=MAX_REQUESTS_PER_CONNECTION {
+        let mut request = match Request::parse_with_limit(&mut reader, MAX_BODY_SIZE) {
+            Ok(request) => request,
+            // client hung up or went idle, nothing to answer
+            Err(ParseError::ConnectionClosed) | Err(ParseError::Io(_)) => return,
+            Err(err) => {
+                // after a bad request we can't trust where the next one starts, so always close
+                println!("Bad request: {}", err);
+                let _ = Response::new(err.status()).write_to(&mut writer, Version::Http11, false);
+                return;
+            }
+        };
+
+        println!("Request: {} {} {}", request.method, request.target, request.version);
+
+        // once we're shutting down, finish this request but don't wait around for another
+        let keep_alive = request.keep_alive()
+            && served < MAX_REQUESTS_PER_CONNECTION
+            && !signal::shutdown_requested();
+        let response = router.handle(&mut request);
+
+        // responses go back in the same order the requests came in, which is
+        // all pipelining needs since we only ever work on one at a time.
+        let written = if request.method == Method::Head {
+            // same headers as a GET would get, but never a body
+            response.write_head_to(&mut writer, request.version, keep_alive)
+        } else {
+            response.write_to(&mut writer, request.version, keep_alive)
+        };
+
+        if written.is_err() || !keep_alive {
+            return;
+        }
+    }
 }
@@ -0,0 +1,27 @@
+/// How urgently a job should be picked up, see `ThreadPool::execute_with_priority`.
+///
+/// Workers always take the highest priority job that's waiting, so a
+/// health check queued as `High` goes ahead of a backlog of page renders.
+/// Lower priority jobs still get their turn, once they've waited longer
+/// than the pool's starvation limit they're run ahead of everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High
+}
+
+impl Priority {
+    // highest first, the order workers look for jobs in
+    pub(crate) const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    // where its jobs live in the queue's per priority arrays
+    pub(crate) fn index(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2
+        }
+    }
+}
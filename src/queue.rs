@@ -0,0 +1,321 @@
+//! The queue jobs wait in between `ThreadPool::execute` and a worker picking them up.
+//!
+//! Instead of one channel every worker fights over, jobs are spread across
+//! a set of shards, each a deque with its own lock. Each worker has a home
+//! shard it takes from first, and steals from the others when that's empty,
+//! so workers mostly aren't contending on the same lock. Locks are only ever
+//! held for a push or a pop, never while waiting for work to turn up: idle
+//! workers park on a condvar instead, and only when the whole queue is empty.
+//!
+//! Each shard keeps a deque per priority. Workers take from the highest one
+//! with anything in it across all the shards, unless a lower priority job
+//! has been waiting longer than the starvation limit, then that goes first.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Job, Priority};
+
+// upper limit on shards, past this more shards just means more places to look when stealing
+const MAX_SHARDS: usize = 64;
+
+/// What a worker got back from `JobQueue::pop`.
+pub(crate) enum Pop {
+    Job(Job),
+    /// Nothing turned up before the timeout.
+    TimedOut,
+    /// Woken up by `wake_all` with nothing to do, so whatever it was woken for can be checked.
+    Woken,
+    /// The queue is closed and empty, time to stop.
+    Closed
+}
+
+// a job and when it was queued, to tell when it's been passed over for too long
+struct Queued {
+    job: Job,
+    since: Instant
+}
+
+// one deque per priority, indexed by Priority::index
+type Shard = [VecDeque<Queued>; 3];
+
+pub(crate) struct JobQueue {
+    shards: Vec<Mutex<Shard>>,
+    // round robin over the shards for pushes
+    next_shard: AtomicUsize,
+    // jobs pushed but not popped yet, across every shard. bumped before the
+    // job goes in, so it can be briefly ahead of what's actually in the shards.
+    pending: AtomicUsize,
+    // the same again split by priority, so workers can skip the ones with nothing in
+    waiting: [AtomicUsize; 3],
+    // how long a lower priority job can be passed over before it goes first
+    starvation_limit: Duration,
+    closed: AtomicBool,
+    // parking for idle workers. `sleepers` lets push skip taking the lock
+    // when nobody is asleep, which is most of the time when it's busy.
+    // the bool is whether a worker has been woken and hasn't got going yet.
+    park: Mutex<bool>,
+    wakeup: Condvar,
+    sleepers: AtomicUsize,
+    // bumped by wake_all, so parked workers can tell they were woken on purpose
+    generation: AtomicUsize
+}
+
+impl JobQueue {
+    /// `shards` would usually be about the number of workers.
+    pub(crate) fn new(shards: usize, starvation_limit: Duration) -> JobQueue {
+        let shards = shards.clamp(1, MAX_SHARDS);
+
+        JobQueue {
+            shards: (0..shards).map(|_| Mutex::new(Default::default())).collect(),
+            next_shard: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            waiting: Default::default(),
+            starvation_limit,
+            closed: AtomicBool::new(false),
+            park: Mutex::new(false),
+            wakeup: Condvar::new(),
+            sleepers: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0)
+        }
+    }
+
+    pub(crate) fn push(&self, job: Job, priority: Priority) {
+        let queued = Queued { job, since: Instant::now() };
+
+        // counted first so a worker about to park sees it and stays awake
+        self.waiting[priority.index()].fetch_add(1, Ordering::SeqCst);
+        let queued_before = self.pending.fetch_add(1, Ordering::SeqCst);
+
+        let shard = self.next_shard.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+        self.shards[shard].lock().unwrap()[priority.index()].push_back(queued);
+
+        // if there was already something queued, whoever was woken for that is awake and
+        // will wake the next worker when it takes it. waking one on every push just has
+        // them all fighting over the cpu with whoever is pushing.
+        if queued_before == 0 {
+            self.wake_one();
+        }
+    }
+
+    // a worker registers as a sleeper before its last look at `pending`, and pushes bump
+    // `pending` before looking at `sleepers`, so one of them always sees the other.
+    fn wake_one(&self) {
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            // taking the lock means any worker part way into parking has finished doing so
+            let mut waking = self.park.lock().unwrap();
+
+            // one worker on its way is enough, it passes it on once it's taken a job.
+            // otherwise every push to a busy queue would be a syscall.
+            if !*waking && self.sleepers.load(Ordering::SeqCst) > 0 {
+                *waking = true;
+                self.wakeup.notify_one();
+            }
+        }
+    }
+
+    /// Get the next job for the worker whose home shard is `home`, waiting up
+    /// to `timeout` for one if the queue is empty.
+    pub(crate) fn pop(&self, home: usize, timeout: Duration) -> Pop {
+        let deadline = Instant::now() + timeout;
+        let generation = self.generation.load(Ordering::SeqCst);
+
+        loop {
+            if let Some(job) = self.try_pop(home) {
+                return Pop::Job(job);
+            }
+
+            // a push can be counted but not in its shard yet. let it finish rather than
+            // spinning on it, which with fewer cores than workers can take a while.
+            if self.pending.load(Ordering::SeqCst) > 0 {
+                thread::yield_now();
+                continue;
+            }
+
+            let mut park = self.park.lock().unwrap();
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+
+            let woken_for = loop {
+                // a push that landed after try_pop, go back round and grab it
+                if self.pending.load(Ordering::SeqCst) > 0 {
+                    break None;
+                }
+                if self.closed.load(Ordering::SeqCst) {
+                    break Some(Pop::Closed);
+                }
+                if self.generation.load(Ordering::SeqCst) != generation {
+                    break Some(Pop::Woken);
+                }
+
+                let now = Instant::now();
+                if now >= deadline {
+                    break Some(Pop::TimedOut);
+                }
+
+                park = self.wakeup.wait_timeout(park, deadline - now).unwrap().0;
+                // whoever was woken is up now (or someone else is, which does just as well)
+                *park = false;
+            };
+
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+
+            if let Some(pop) = woken_for {
+                return pop;
+            }
+        }
+    }
+
+    // take a job without waiting, home shard first and then stealing from the rest
+    fn try_pop(&self, home: usize) -> Option<Job> {
+        if self.pending.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        let job = self.take_starved(home).or_else(|| self.take_highest(home))?;
+
+        // still more to do, so get another worker going on it
+        if self.pending.fetch_sub(1, Ordering::SeqCst) > 1 {
+            self.wake_one();
+        }
+        Some(job)
+    }
+
+    // a lower priority job that's been waiting longer than the starvation limit, if there is one
+    fn take_starved(&self, home: usize) -> Option<Job> {
+        let mut now = None;
+
+        for (level, priority) in Priority::ALL.into_iter().enumerate().skip(1).rev() {
+            if self.waiting[priority.index()].load(Ordering::SeqCst) == 0 {
+                continue;
+            }
+
+            // with nothing more urgent waiting it'd be picked anyway, so don't bother with the clock
+            let higher_waiting = Priority::ALL[..level]
+                .iter()
+                .any(|higher| self.waiting[higher.index()].load(Ordering::SeqCst) > 0);
+            if !higher_waiting {
+                continue;
+            }
+
+            let now = *now.get_or_insert_with(Instant::now);
+            let starved = self.take(home, priority, |queued| now.duration_since(queued.since) >= self.starvation_limit);
+            if starved.is_some() {
+                return starved;
+            }
+        }
+
+        None
+    }
+
+    fn take_highest(&self, home: usize) -> Option<Job> {
+        Priority::ALL
+            .into_iter()
+            .filter(|priority| self.waiting[priority.index()].load(Ordering::SeqCst) > 0)
+            .find_map(|priority| self.take(home, priority, |_| true))
+    }
+
+    // take the oldest job at `priority` from the first shard where `wanted` says yes to it
+    fn take<F: Fn(&Queued) -> bool>(&self, home: usize, priority: Priority, wanted: F) -> Option<Job> {
+        let count = self.shards.len();
+
+        for offset in 0..count {
+            let mut shard = self.shards[(home + offset) % count].lock().unwrap();
+            let level = &mut shard[priority.index()];
+
+            if level.front().is_some_and(&wanted) {
+                let queued = level.pop_front()?;
+                self.waiting[priority.index()].fetch_sub(1, Ordering::SeqCst);
+                return Some(queued.job);
+            }
+        }
+
+        None
+    }
+
+    /// Wake every parked worker so it can re-check whatever it needs to, e.g.
+    /// whether the pool has shrunk and it should stop.
+    pub(crate) fn wake_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        let _park = self.park.lock().unwrap();
+        self.wakeup.notify_all();
+    }
+
+    /// Shut the queue. Jobs already in it still get handed out, but once it's
+    /// empty every worker gets `Pop::Closed` instead of waiting for more.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Priority, ThreadPool};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // a single worker pool, and a sender that lets its worker go once it's stuck on the first job
+    fn blocked_pool(starvation_limit: Duration) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::builder().size(1).starvation_limit(starvation_limit).build().unwrap();
+
+        let (started_tx, started) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        // everything queued from here on has to wait behind it
+        started.recv().unwrap();
+
+        (pool, release)
+    }
+
+    // queue a job that notes down `name` when it runs
+    fn record(pool: &ThreadPool, ran: &Arc<Mutex<Vec<&'static str>>>, priority: Priority, name: &'static str) {
+        let ran = Arc::clone(ran);
+        pool.execute_with_priority(priority, move || ran.lock().unwrap().push(name));
+    }
+
+    #[test]
+    fn runs_the_highest_priority_first() {
+        let (pool, release) = blocked_pool(Duration::from_secs(60));
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        record(&pool, &ran, Priority::Low, "low 1");
+        record(&pool, &ran, Priority::Normal, "normal 1");
+        record(&pool, &ran, Priority::High, "high 1");
+        record(&pool, &ran, Priority::Low, "low 2");
+        record(&pool, &ran, Priority::High, "high 2");
+        record(&pool, &ran, Priority::Normal, "normal 2");
+
+        release.send(()).unwrap();
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+
+        // and oldest first within each priority
+        assert_eq!(*ran.lock().unwrap(), ["high 1", "high 2", "normal 1", "normal 2", "low 1", "low 2"]);
+    }
+
+    #[test]
+    fn a_starved_job_goes_ahead_of_higher_ones() {
+        let (pool, release) = blocked_pool(Duration::from_millis(50));
+        let ran = Arc::new(Mutex::new(Vec::new()));
+
+        record(&pool, &ran, Priority::Low, "starved");
+        thread::sleep(Duration::from_millis(100));
+        record(&pool, &ran, Priority::High, "high 1");
+        record(&pool, &ran, Priority::High, "high 2");
+        // only just queued, so it still waits its turn
+        record(&pool, &ran, Priority::Low, "fresh");
+
+        release.send(()).unwrap();
+        assert!(pool.wait_idle_timeout(Duration::from_secs(5)));
+
+        assert_eq!(*ran.lock().unwrap(), ["starved", "high 1", "high 2", "fresh"]);
+    }
+}
@@ -0,0 +1,318 @@
+//! Range requests, for fetching just part of a response, e.g. to resume a
+//! download that got cut off or to skip around in a video.
+//!
+//! Only byte ranges of responses whose length is known up front are served,
+//! a streamed body always goes out whole.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::conditional::parse_etags;
+use crate::date::parse_http_date;
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+use crate::status::StatusCode;
+
+// more ranges than this in one request is someone trying to make us do a lot
+// of seeking for nothing, so they just get the whole thing instead
+const MAX_RANGES: usize = 16;
+
+// mixed into multipart boundaries so two responses never share one
+static NEXT_BOUNDARY: AtomicU64 = AtomicU64::new(0);
+
+// cut a 200 down to the parts the request's Range header asks for. only GET
+// gets ranges, but a HEAD gets the same `Accept-Ranges: bytes` it would, so
+// clients know they can ask. nothing else is a representation to take a range of.
+pub(crate) fn evaluate(request: &Request, mut response: Response) -> Response {
+    if response.status != StatusCode::Ok || !matches!(request.method, Method::Get | Method::Head) {
+        return response;
+    }
+
+    let length = match &response.body {
+        Body::Bytes(contents) => contents.len() as u64,
+        Body::File(file) => match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return response
+        },
+        Body::Stream(_) => return response
+    };
+
+    response.headers.insert("Accept-Ranges", "bytes");
+
+    if request.method == Method::Head {
+        return response;
+    }
+
+    let range = match request.headers.get("Range") {
+        Some(range) => range,
+        None => return response
+    };
+
+    // If-Range means "only if it hasn't changed, otherwise send me all of it"
+    if let Some(if_range) = request.headers.get("If-Range") {
+        if !if_range_matches(if_range, &response) {
+            return response;
+        }
+    }
+
+    // a header we can't make sense of is ignored, which the RFC allows
+    let ranges = match parse_ranges(range, length) {
+        Some(ranges) => ranges,
+        None => return response
+    };
+
+    if ranges.is_empty() {
+        return Response::new(StatusCode::RangeNotSatisfiable).header("Content-Range", &format!("bytes */{}", length));
+    }
+
+    let Response { headers, body, .. } = response;
+    let mut partial = Response { status: StatusCode::PartialContent, headers, body: Body::Bytes(Vec::new()) };
+
+    let pieces = if let [(first, last)] = ranges[..] {
+        partial.headers.insert("Content-Range", &format!("bytes {}-{}/{}", first, last, length));
+        vec![Piece::Range { start: first, remaining: last - first + 1, seeked: false }]
+    } else {
+        multipart(&mut partial, &ranges, length)
+    };
+
+    let content_length: u64 = pieces.iter().map(Piece::len).sum();
+    partial.headers.insert("Content-Length", &content_length.to_string());
+
+    partial.body = match body {
+        Body::Bytes(contents) => Body::Stream(Box::new(Pieces::new(Cursor::new(contents), pieces))),
+        Body::File(file) => Body::Stream(Box::new(Pieces::new(file, pieces))),
+        Body::Stream(_) => unreachable!("streams were turned away above")
+    };
+
+    partial
+}
+
+// an etag has to match exactly, strongly, and a date has to be the Last-Modified
+// exactly. anything weaker can't promise the parts will fit together.
+fn if_range_matches(if_range: &str, response: &Response) -> bool {
+    let if_range = if_range.trim();
+
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        let etag = response.headers.get("ETag").map(parse_etags).unwrap_or_default();
+        let wanted = parse_etags(if_range);
+
+        return matches!((etag.first(), wanted.first()), (Some(etag), Some(wanted)) if etag.strong_eq(wanted));
+    }
+
+    let last_modified = response.headers.get("Last-Modified").and_then(parse_http_date);
+    matches!((last_modified, parse_http_date(if_range)), (Some(modified), Some(since)) if modified == since)
+}
+
+// `bytes=0-99, 200-, -50` into inclusive (first, last) pairs, leaving out the
+// ones that start past the end. overlapping or touching ranges are merged, so
+// asking for the same bytes over and over doesn't get them sent over and over.
+// None if it isn't a byte range header at all or there's something wrong with it.
+fn parse_ranges(header: &str, length: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+
+        let range = if first.is_empty() {
+            // `-50` is the last 50 bytes
+            let suffix = position(last)?;
+            if suffix == 0 || length == 0 {
+                None
+            } else {
+                Some((length.saturating_sub(suffix), length - 1))
+            }
+        } else {
+            let first = position(first)?;
+            let last = if last.is_empty() { u64::MAX } else { position(last)? };
+            if last < first {
+                return None;
+            }
+
+            // a last past the end just means up to the end
+            if first < length {
+                Some((first, cmp::min(last, length - 1)))
+            } else {
+                None
+            }
+        };
+
+        ranges.extend(range);
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1.saturating_add(1) => previous.1 = cmp::max(previous.1, last),
+            _ => merged.push((first, last))
+        }
+    }
+
+    if merged.len() > MAX_RANGES {
+        return None;
+    }
+
+    Some(merged)
+}
+
+// a byte position, all digits. one too big for a u64 is still a position, just
+// one past the end of anything we could be sending.
+fn position(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(s.parse().unwrap_or(u64::MAX))
+}
+
+// turn `partial` into a multipart/byteranges response, and return the pieces
+// its body is made of. each part gets its own Content-Range, and the
+// Content-Type the whole thing would have had.
+fn multipart(partial: &mut Response, ranges: &[(u64, u64)], length: u64) -> Vec<Piece> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    let boundary = format!("{:016x}{:08x}", nanos, NEXT_BOUNDARY.fetch_add(1, Ordering::Relaxed));
+
+    let content_type = partial.headers.get("Content-Type").map(str::to_string);
+    partial.headers.insert("Content-Type", &format!("multipart/byteranges; boundary={}", boundary));
+
+    let mut pieces = Vec::new();
+
+    for &(first, last) in ranges {
+        let mut head = format!("\r\n--{}\r\n", boundary);
+        if let Some(content_type) = &content_type {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n", first, last, length));
+
+        pieces.push(Piece::Text(head.into_bytes(), 0));
+        pieces.push(Piece::Range { start: first, remaining: last - first + 1, seeked: false });
+    }
+
+    pieces.push(Piece::Text(format!("\r\n--{}--\r\n", boundary).into_bytes(), 0));
+    pieces
+}
+
+enum Piece {
+    // some bytes of our own, and how far through them we are
+    Text(Vec<u8>, usize),
+    // a part of the source. it only gets seeked to once we reach it.
+    Range { start: u64, remaining: u64, seeked: bool }
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Text(text, _) => text.len() as u64,
+            Piece::Range { remaining, .. } => *remaining
+        }
+    }
+}
+
+// reads the pieces one after another, pulling the ranges out of `source` as it
+// gets to them, so a file is never read any more than the parts asked for
+struct Pieces<R> {
+    source: R,
+    pieces: VecDeque<Piece>
+}
+
+impl<R: Read + Seek> Pieces<R> {
+    fn new(source: R, pieces: Vec<Piece>) -> Pieces<R> {
+        Pieces { source, pieces: pieces.into() }
+    }
+}
+
+impl<R: Read + Seek> Read for Pieces<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(piece) = self.pieces.front_mut() {
+            let read = match piece {
+                Piece::Text(text, position) => {
+                    let read = (&text[*position..]).read(buf)?;
+                    *position += read;
+                    read
+                },
+                Piece::Range { start, remaining, seeked } => {
+                    if *remaining == 0 {
+                        0
+                    } else {
+                        if !*seeked {
+                            self.source.seek(SeekFrom::Start(*start))?;
+                            *seeked = true;
+                        }
+
+                        let wanted = cmp::min(buf.len() as u64, *remaining) as usize;
+                        let read = self.source.read(&mut buf[..wanted])?;
+                        if read == 0 && wanted > 0 {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while it was being sent"));
+                        }
+                        *remaining -= read as u64;
+                        read
+                    }
+                }
+            };
+
+            // nothing read means this piece is used up, go on to the next
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.pieces.pop_front();
+        }
+
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_three_kinds_of_range() {
+        assert_eq!(parse_ranges("bytes=0-9", 100), Some(vec![(0, 9)]));
+        assert_eq!(parse_ranges("bytes=90-", 100), Some(vec![(90, 99)]));
+        assert_eq!(parse_ranges("bytes=-5", 100), Some(vec![(95, 99)]));
+        assert_eq!(parse_ranges("Bytes = 0-0, 50-59", 100), Some(vec![(0, 0), (50, 59)]));
+    }
+
+    #[test]
+    fn clamps_to_the_end_and_drops_what_starts_past_it() {
+        assert_eq!(parse_ranges("bytes=90-200", 100), Some(vec![(90, 99)]));
+        assert_eq!(parse_ranges("bytes=-500", 100), Some(vec![(0, 99)]));
+        assert_eq!(parse_ranges("bytes=100-, 0-1", 100), Some(vec![(0, 1)]));
+        assert_eq!(parse_ranges("bytes=100-", 100), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=-0", 100), Some(vec![]));
+    }
+
+    #[test]
+    fn positions_too_big_for_a_u64_mean_the_end() {
+        assert_eq!(parse_ranges("bytes=10-99999999999999999999999", 100), Some(vec![(10, 99)]));
+        assert_eq!(parse_ranges("bytes=-99999999999999999999999", 100), Some(vec![(0, 99)]));
+        assert_eq!(parse_ranges("bytes=99999999999999999999999-", 100), Some(vec![]));
+    }
+
+    #[test]
+    fn merges_overlapping_and_touching_ranges() {
+        let repeated = vec!["0-"; MAX_RANGES * 2].join(",");
+        assert_eq!(parse_ranges(&format!("bytes={}", repeated), 100), Some(vec![(0, 99)]));
+
+        assert_eq!(parse_ranges("bytes=50-59, 0-9, 10-19, 5-12, 55-70", 100), Some(vec![(0, 19), (50, 70)]));
+        assert_eq!(parse_ranges("bytes=0-9, 11-19", 100), Some(vec![(0, 9), (11, 19)]));
+    }
+
+    #[test]
+    fn ignores_headers_it_cant_make_sense_of() {
+        for header in ["lines=0-9", "bytes", "bytes=9-0", "bytes=a-b", "bytes=+1-2", "bytes=0-9;x", "bytes=5"] {
+            assert_eq!(parse_ranges(header, 100), None, "{:?}", header);
+        }
+
+        let scattered: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+        assert_eq!(parse_ranges(&format!("bytes={}", scattered.join(",")), 100), None);
+    }
+}
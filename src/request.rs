@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::str::FromStr;
+
+use crate::chunked;
+use crate::headers::Headers;
+use crate::status::StatusCode;
+
+// nobody legit sends a request line or header this long, and without a cap
+// a client could just stream bytes at us forever without a newline.
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+pub(crate) const MAX_HEADERS: usize = 100;
+
+/// How much of a request body we're willing to hold in memory when nobody says otherwise.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// how much to pull off the stream per read while filling in a body
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH"
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    // methods are case sensitive, `get` is not the same thing as `GET`.
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(ParseError::InvalidMethod(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    Http10,
+    Http11
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1"
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Version, ParseError> {
+        match s.as_bytes() {
+            b"HTTP/1.0" => Ok(Version::Http10),
+            b"HTTP/1.1" => Ok(Version::Http11),
+            // a proper version, just not one we speak
+            [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                Err(ParseError::UnsupportedVersion(s.to_string()))
+            },
+            _ => Err(ParseError::InvalidVersion(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Everything that can go wrong reading a request off the wire.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection closed before a request line was sent. Not really an
+    /// error, the client is just done with us.
+    ConnectionClosed,
+    /// The connection closed or timed out part way through a request.
+    Io(io::Error),
+    InvalidRequestLine(String),
+    InvalidMethod(String),
+    InvalidVersion(String),
+    /// A well formed `HTTP/x.y` that isn't 1.0 or 1.1.
+    UnsupportedVersion(String),
+    InvalidHeader(String),
+    InvalidContentLength(String),
+    InvalidChunk(String),
+    /// A `Transfer-Encoding` other than `chunked`, which is the only one we understand.
+    UnsupportedTransferEncoding(String),
+    LineTooLong,
+    TooManyHeaders,
+    /// The body is bigger than the configured maximum. Holds the length the client asked for.
+    PayloadTooLarge(u64)
+}
+
+impl ParseError {
+    /// The status to answer with when this error happens.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::PayloadTooLarge(_) => StatusCode::PayloadTooLarge,
+            ParseError::UnsupportedTransferEncoding(_) => StatusCode::NotImplemented,
+            ParseError::UnsupportedVersion(_) => StatusCode::HttpVersionNotSupported,
+            _ => StatusCode::BadRequest
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => write!(f, "connection closed"),
+            ParseError::Io(err) => write!(f, "io error: {}", err),
+            ParseError::InvalidRequestLine(line) => write!(f, "invalid request line: {:?}", line),
+            ParseError::InvalidMethod(method) => write!(f, "invalid method: {:?}", method),
+            ParseError::InvalidVersion(version) => write!(f, "invalid http version: {:?}", version),
+            ParseError::UnsupportedVersion(version) => write!(f, "unsupported http version: {:?}", version),
+            ParseError::InvalidHeader(header) => write!(f, "invalid header: {:?}", header),
+            ParseError::InvalidContentLength(value) => write!(f, "invalid content-length: {:?}", value),
+            ParseError::InvalidChunk(line) => write!(f, "invalid chunk: {:?}", line),
+            ParseError::UnsupportedTransferEncoding(value) => write!(f, "unsupported transfer-encoding: {:?}", value),
+            ParseError::LineTooLong => write!(f, "line too long"),
+            ParseError::TooManyHeaders => write!(f, "too many headers"),
+            ParseError::PayloadTooLarge(length) => write!(f, "payload of {} bytes is too large", length)
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
+
+/// A parsed HTTP/1.x request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as sent, e.g. `/users/1?page=2`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Values captured from the path by the `Router`, e.g. `id` for `/users/:id`.
+    pub params: HashMap<String, String>
+}
+
+impl Request {
+    /// Read a single request from `reader`, allowing bodies up to `DEFAULT_MAX_BODY_SIZE`.
+    ///
+    /// Only as many bytes as the request takes up are consumed, so whatever
+    /// comes after it is left in the reader.
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::parse_with_limit(reader, DEFAULT_MAX_BODY_SIZE)
+    }
+
+    /// Read a single request from `reader`, refusing bodies bigger than `max_body_size` bytes.
+    ///
+    /// The size is checked against `Content-Length` before any of the body is read,
+    /// so an oversized body gets `ParseError::PayloadTooLarge` rather than being
+    /// truncated, and is left unread in the reader. Chunked bodies are checked
+    /// as each chunk comes in.
+    pub fn parse_with_limit<R: BufRead>(reader: &mut R, max_body_size: usize) -> Result<Request, ParseError> {
+        let request_line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Err(ParseError::ConnectionClosed)
+        };
+
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) if !target.is_empty() => {
+                (method, target, version)
+            },
+            _ => return Err(ParseError::InvalidRequestLine(request_line))
+        };
+
+        let method = method.parse()?;
+        let version = version.parse()?;
+        let target = target.to_string();
+
+        let mut headers = Headers::new();
+
+        loop {
+            let line = match read_line(reader)? {
+                Some(line) => line,
+                None => return Err(unexpected_eof().into())
+            };
+
+            // blank line means the headers are done
+            if line.is_empty() {
+                break;
+            }
+
+            if headers.len() == MAX_HEADERS {
+                return Err(ParseError::TooManyHeaders);
+            }
+
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) if is_token(name) => (name, value.trim()),
+                _ => return Err(ParseError::InvalidHeader(line))
+            };
+
+            headers.append(name, value);
+        }
+
+        let body = if headers.contains("Transfer-Encoding") {
+            // every copy of the header counts, a second one could be hiding another encoding
+            let encoding = headers.get_all("Transfer-Encoding").collect::<Vec<&str>>().join(", ");
+            if !encoding.trim().eq_ignore_ascii_case("chunked") {
+                return Err(ParseError::UnsupportedTransferEncoding(encoding));
+            }
+            // having both is how request smuggling happens, the two ends of the
+            // connection can disagree about where the body stops. just refuse it.
+            if headers.contains("Content-Length") {
+                return Err(ParseError::InvalidContentLength("sent alongside Transfer-Encoding".to_string()));
+            }
+
+            chunked::read_chunked_body(reader, max_body_size)?
+        } else {
+            match content_length(&headers)? {
+                Some(length) if length > max_body_size as u64 => {
+                    return Err(ParseError::PayloadTooLarge(length));
+                },
+                Some(length) => read_body(reader, length as usize)?,
+                None => Vec::new()
+            }
+        };
+
+        Ok(Request { method, target, version, headers, body, params: HashMap::new() })
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    ///
+    /// HTTP/1.1 connections stay open unless the client sends `Connection: close`,
+    /// HTTP/1.0 ones close unless it sends `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        // Connection is a comma separated list, e.g. `keep-alive, Upgrade`
+        let has_option = |option: &str| {
+            self.headers
+                .get_all("Connection")
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        };
+
+        match self.version {
+            Version::Http11 => !has_option("close"),
+            Version::Http10 => has_option("keep-alive")
+        }
+    }
+
+    /// Get a path param captured by the `Router`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    /// The path part of the target, without any query string.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target
+        }
+    }
+
+    /// The query string part of the target, without the `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+}
+
+// the Content-Length header as a number. sending it more than once is only
+// allowed if every copy agrees, otherwise we can't know where the body ends.
+fn content_length(headers: &Headers) -> Result<Option<u64>, ParseError> {
+    let mut length = None;
+
+    for value in headers.get_all("Content-Length") {
+        // parse would accept a leading +, the spec doesn't
+        let parsed = if value.bytes().all(|b| b.is_ascii_digit()) {
+            value.parse::<u64>().ok()
+        } else {
+            None
+        };
+
+        match (parsed, length) {
+            (None, _) => return Err(ParseError::InvalidContentLength(value.to_string())),
+            (Some(parsed), Some(existing)) if parsed != existing => {
+                return Err(ParseError::InvalidContentLength(value.to_string()));
+            },
+            (parsed, _) => length = parsed
+        }
+    }
+
+    Ok(length)
+}
+
+// keep reading until we have `length` bytes. a single read only hands back
+// whatever happens to have arrived so far, which for a big body is rarely all of it.
+fn read_body<R: Read>(reader: &mut R, length: usize) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::with_capacity(length);
+    let mut chunk = [0; READ_CHUNK_SIZE];
+
+    while body.len() < length {
+        let wanted = (length - body.len()).min(READ_CHUNK_SIZE);
+
+        match reader.read(&mut chunk[..wanted]) {
+            // the client hung up before sending everything it promised
+            Ok(0) => return Err(unexpected_eof().into()),
+            Ok(read) => body.extend_from_slice(&chunk[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into())
+        }
+    }
+
+    Ok(body)
+}
+
+// read up to and including the next \n, handing back the line without its line ending.
+// returns None if the reader was already at the end.
+pub(crate) fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    // + 1 so we can tell the difference between a line that is exactly the max and one that is over
+    let read = reader
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+
+    if line.last() != Some(&b'\n') {
+        return if line.len() > MAX_LINE_LENGTH {
+            Err(ParseError::LineTooLong)
+        } else {
+            Err(unexpected_eof().into())
+        };
+    }
+
+    line.pop();
+    // the spec says \r\n but be lenient and allow a bare \n too
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|err| ParseError::InvalidRequestLine(String::from_utf8_lossy(err.as_bytes()).into_owned()))
+}
+
+// header names can't be empty or have spaces / separators in them
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+pub(crate) fn unexpected_eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid request")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::parse(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn unsupported_versions_get_a_505_and_garbage_gets_a_400() {
+        let err = parse("GET / HTTP/2.0\r\n\r\n").unwrap_err();
+        assert!(matches!(err, ParseError::UnsupportedVersion(_)));
+        assert_eq!(err.status(), StatusCode::HttpVersionNotSupported);
+
+        for version in ["HTTP/2", "HTTP/1.1.1", "http/1.1", "HTTP/a.b"] {
+            let err = parse(&format!("GET / {}\r\n\r\n", version)).unwrap_err();
+            assert!(matches!(err, ParseError::InvalidVersion(_)), "{}", version);
+            assert_eq!(err.status(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn parses_a_simple_request() {
+        let request = parse("GET /users/1?page=2 HTTP/1.1\r\nHost: example.com\r\nX-Two:  spaced  \r\n\r\n").unwrap();
+
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path(), "/users/1");
+        assert_eq!(request.query(), Some("page=2"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("host"), Some("example.com"));
+        assert_eq!(request.headers.get("X-Two"), Some("spaced"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn leaves_pipelined_requests_in_the_reader() {
+        let mut reader = "POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /b HTTP/1.1\r\n\r\n".as_bytes();
+
+        assert_eq!(Request::parse(&mut reader).unwrap().body, b"abc");
+        assert_eq!(Request::parse(&mut reader).unwrap().target, "/b");
+        assert!(matches!(Request::parse(&mut reader), Err(ParseError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn rejects_bad_request_lines() {
+        for line in ["GET /", "GET  / HTTP/1.1", "GET / HTTP/1.1 extra", "get / HTTP/1.1", "BREW / HTTP/1.1"] {
+            assert!(parse(&format!("{}\r\n\r\n", line)).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        for header in ["no colon", "Space Name: x", "Host : x", ": empty", " folded: x"] {
+            let err = parse(&format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header)).unwrap_err();
+            assert!(matches!(err, ParseError::InvalidHeader(_)), "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn caps_line_length_and_header_count() {
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        assert!(matches!(parse(&long), Err(ParseError::LineTooLong)));
+
+        // a line with no newline at all still stops at the cap instead of reading forever
+        let endless = "a".repeat(MAX_LINE_LENGTH * 4);
+        assert!(matches!(parse(&endless), Err(ParseError::LineTooLong)));
+
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS + 1));
+        assert!(matches!(parse(&many), Err(ParseError::TooManyHeaders)));
+    }
+
+    #[test]
+    fn content_length_has_to_be_digits_and_agree() {
+        let ok = parse("POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi").unwrap();
+        assert_eq!(ok.body, b"hi");
+
+        for lengths in ["2\r\nContent-Length: 3", "+2", "-1", "2, 2", " ", "0x2", "99999999999999999999999"] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhi", lengths);
+            assert!(matches!(parse(&raw), Err(ParseError::InvalidContentLength(_))), "{:?}", lengths);
+        }
+    }
+
+    #[test]
+    fn refuses_content_length_with_transfer_encoding() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidContentLength(_))));
+    }
+
+    #[test]
+    fn only_plain_chunked_transfer_encoding_is_understood() {
+        for encodings in ["gzip", "gzip, chunked", "chunked\r\nTransfer-Encoding: gzip", "chunked, chunked"] {
+            let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n0\r\n\r\n", encodings);
+            let err = parse(&raw).unwrap_err();
+            assert!(matches!(err, ParseError::UnsupportedTransferEncoding(_)), "{:?}", encodings);
+            assert_eq!(err.status(), StatusCode::NotImplemented);
+        }
+    }
+
+    #[test]
+    fn body_size_is_checked_before_reading() {
+        let mut reader = "POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world".as_bytes();
+        let err = Request::parse_with_limit(&mut reader, 10).unwrap_err();
+
+        assert!(matches!(err, ParseError::PayloadTooLarge(11)));
+        assert_eq!(err.status(), StatusCode::PayloadTooLarge);
+        // nothing of the body was read
+        assert_eq!(reader, b"hello world");
+    }
+
+    #[test]
+    fn a_short_body_is_an_error() {
+        let err = parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhi").unwrap_err();
+        assert!(matches!(err, ParseError::Io(ref err) if err.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn keep_alive_defaults_by_version() {
+        assert!(parse("GET / HTTP/1.1\r\n\r\n").unwrap().keep_alive());
+        assert!(!parse("GET / HTTP/1.1\r\nConnection: Upgrade, close\r\n\r\n").unwrap().keep_alive());
+        assert!(!parse("GET / HTTP/1.0\r\n\r\n").unwrap().keep_alive());
+        assert!(parse("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().keep_alive());
+    }
+}
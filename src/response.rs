@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::SystemTime;
+
+use crate::chunked::ChunkedWriter;
+use crate::date::http_date;
+use crate::headers::Headers;
+use crate::request::Version;
+use crate::status::StatusCode;
+
+// what goes in the Server header unless the response already has one
+const SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// What goes after the headers of a response.
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// A file on disk. Its length is known up front, so it goes out with a
+    /// Content-Length but is still copied across without loading it all in.
+    File(File),
+    /// A body we don't know the length of up front. Goes out chunked to
+    /// HTTP/1.1 clients so it never has to be loaded into memory all at once.
+    ///
+    /// If the response already has a `Content-Length` the stream is trusted
+    /// to be exactly that long, and is sent as it is instead.
+    Stream(Box<dyn Read + Send>)
+}
+
+/// A response waiting to be written back to the client.
+///
+/// `Content-Length` (or chunked encoding), `Date` and `Server` are filled in
+/// when it's written, so handlers only have to set the headers they care about.
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body
+}
+
+impl Response {
+    /// Create a response with no headers and an empty body.
+    pub fn new(status: StatusCode) -> Response {
+        Response::with_body(status, Vec::new())
+    }
+
+    pub fn with_body<B: Into<Vec<u8>>>(status: StatusCode, body: B) -> Response {
+        Response { status, headers: Headers::new(), body: Body::Bytes(body.into()) }
+    }
+
+    pub fn with_file(status: StatusCode, file: File) -> Response {
+        Response { status, headers: Headers::new(), body: Body::File(file) }
+    }
+
+    pub fn with_stream<R: Read + Send + 'static>(status: StatusCode, reader: R) -> Response {
+        Response { status, headers: Headers::new(), body: Body::Stream(Box::new(reader)) }
+    }
+
+    /// Set a header, replacing any already there with the same name.
+    /// Takes and hands back the response so calls can be chained.
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Write the whole response out to `stream`.
+    ///
+    /// `version` is the version of the request being answered, which decides
+    /// whether a streamed body can go out chunked. `keep_alive` is whether the
+    /// connection is staying open afterwards, so the client can be told.
+    pub fn write_to<W: Write>(self, stream: &mut W, version: Version, keep_alive: bool) -> io::Result<()> {
+        self.write(stream, version, keep_alive, true)
+    }
+
+    /// Write only the status line and headers, for answering a HEAD request.
+    ///
+    /// The headers are the same ones `write_to` would send, `Content-Length`
+    /// included, but the body is never read.
+    pub fn write_head_to<W: Write>(self, stream: &mut W, version: Version, keep_alive: bool) -> io::Result<()> {
+        self.write(stream, version, keep_alive, false)
+    }
+
+    fn write<W: Write>(self, stream: &mut W, version: Version, keep_alive: bool, with_body: bool) -> io::Result<()> {
+        let Response { status, mut headers, body } = self;
+
+        // 1.1 assumes keep-alive and 1.0 assumes close, so only say so when going against the default
+        match (version, keep_alive) {
+            (Version::Http11, false) => headers.insert("Connection", "close"),
+            (Version::Http10, true) => headers.insert("Connection", "keep-alive"),
+            _ => {}
+        }
+
+        if !headers.contains("Date") {
+            headers.insert("Date", &http_date(SystemTime::now()));
+        }
+        if !headers.contains("Server") {
+            headers.insert("Server", SERVER);
+        }
+
+        // the framing headers are ours to set, whatever the handler put there, or the
+        // client could end up with both and read the body differently to how it's sent.
+        // the one exception is a Content-Length on a stream, which says how much of it to send.
+        let stream_length = match body {
+            Body::Stream(_) => content_length(&headers),
+            _ => None
+        };
+        headers.remove("Content-Length");
+        headers.remove("Transfer-Encoding");
+
+        // 204s and 304s don't get a body or a length, whatever the handler gave them
+        if !status.allows_body() {
+            write!(stream, "HTTP/1.1 {}\r\n{}\r\n", status, headers)?;
+            return stream.flush();
+        }
+
+        let body = match body {
+            // http/1.0 clients don't know about chunked encoding, so they still get
+            // the whole thing with a Content-Length up front.
+            Body::Stream(mut reader) if version == Version::Http10 && with_body && stream_length.is_none() => {
+                let mut contents = Vec::new();
+                reader.read_to_end(&mut contents)?;
+                Body::Bytes(contents)
+            },
+            body => body
+        };
+
+        match body {
+            Body::Bytes(contents) => {
+                headers.insert("Content-Length", &contents.len().to_string());
+                write!(stream, "HTTP/1.1 {}\r\n{}\r\n", status, headers)?;
+                if with_body {
+                    stream.write_all(&contents)?;
+                }
+            },
+            Body::File(file) => {
+                let length = file.metadata()?.len();
+                headers.insert("Content-Length", &length.to_string());
+                write!(stream, "HTTP/1.1 {}\r\n{}\r\n", status, headers)?;
+
+                if with_body {
+                    // only as much as we promised, in case it's grown since
+                    let copied = io::copy(&mut file.take(length), stream)?;
+                    if copied < length {
+                        // it shrank, the client is left waiting on bytes that aren't coming
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while it was being sent"));
+                    }
+                }
+            },
+            Body::Stream(mut reader) => match stream_length {
+                // a stream that says how long it is doesn't need chunking
+                Some(length) => {
+                    headers.insert("Content-Length", &length.to_string());
+                    write!(stream, "HTTP/1.1 {}\r\n{}\r\n", status, headers)?;
+
+                    if with_body {
+                        let copied = io::copy(&mut reader.take(length), stream)?;
+                        if copied < length {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended before its Content-Length"));
+                        }
+                    }
+                },
+                None => {
+                    // stream it straight out in chunks rather than loading it all just to count it.
+                    // (only a HEAD from a 1.0 client gets here without, and that just goes without a length)
+                    if version == Version::Http11 {
+                        headers.insert("Transfer-Encoding", "chunked");
+                    }
+                    write!(stream, "HTTP/1.1 {}\r\n{}\r\n", status, headers)?;
+
+                    if with_body {
+                        let mut body = ChunkedWriter::new(&mut *stream);
+                        io::copy(&mut reader, &mut body)?;
+                        body.finish()?;
+                    }
+                }
+            }
+        }
+
+        stream.flush()
+    }
+}
+
+// the handler's Content-Length, if it set exactly one and it's a plain number
+fn content_length(headers: &Headers) -> Option<u64> {
+    let mut lengths = headers.get_all("Content-Length");
+    let length = lengths.next()?.trim();
+    if lengths.next().is_some() || length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    length.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // the head as lines, and the body
+    fn written(response: Response, version: Version, head_only: bool) -> (Vec<String>, Vec<u8>) {
+        let mut output = Vec::new();
+        if head_only {
+            response.write_head_to(&mut output, version, true).unwrap();
+        } else {
+            response.write_to(&mut output, version, true).unwrap();
+        }
+
+        let end = output.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(output[..end].to_vec()).unwrap();
+        let lines = head.split("\r\n").filter(|line| !line.is_empty()).map(str::to_string).collect();
+        (lines, output[end..].to_vec())
+    }
+
+    // every value sent for `name`
+    fn values<'a>(head: &'a [String], name: &str) -> Vec<&'a str> {
+        head.iter()
+            .filter_map(|line| line.split_once(": "))
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    // a handler that got its framing headers wrong
+    fn misframed(response: Response) -> Response {
+        response.header("Content-Length", "x").header("Transfer-Encoding", "gzip")
+    }
+
+    #[test]
+    fn bytes_get_their_real_length_and_nothing_else() {
+        let (head, body) = written(misframed(Response::with_body(StatusCode::Ok, "hello")), Version::Http11, false);
+
+        assert_eq!(head[0], "HTTP/1.1 200 OK");
+        assert_eq!(values(&head, "Content-Length"), ["5"]);
+        assert!(values(&head, "Transfer-Encoding").is_empty());
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn files_get_their_real_length_and_nothing_else() {
+        let path = env::temp_dir().join(format!("rust-webserver-response-{}", process::id()));
+        fs::write(&path, "some file").unwrap();
+        let response = misframed(Response::with_file(StatusCode::Ok, File::open(&path).unwrap()));
+        let (head, body) = written(response, Version::Http11, false);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(values(&head, "Content-Length"), ["9"]);
+        assert!(values(&head, "Transfer-Encoding").is_empty());
+        assert_eq!(body, b"some file");
+    }
+
+    #[test]
+    fn head_gets_the_length_but_no_body() {
+        let (head, body) = written(Response::with_body(StatusCode::Ok, "hello"), Version::Http11, true);
+
+        assert_eq!(values(&head, "Content-Length"), ["5"]);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn no_content_and_not_modified_get_no_framing_at_all() {
+        for status in [StatusCode::NoContent, StatusCode::NotModified] {
+            let response = misframed(Response::with_body(status, "ignored"));
+            let (head, body) = written(response, Version::Http11, false);
+
+            assert!(values(&head, "Content-Length").is_empty(), "{}", status);
+            assert!(values(&head, "Transfer-Encoding").is_empty(), "{}", status);
+            assert!(body.is_empty(), "{}", status);
+        }
+    }
+
+    #[test]
+    fn streams_go_out_chunked_to_http11() {
+        let response = misframed(Response::with_stream(StatusCode::Ok, &b"streamed"[..]));
+        let (head, body) = written(response, Version::Http11, false);
+
+        assert!(values(&head, "Content-Length").is_empty());
+        assert_eq!(values(&head, "Transfer-Encoding"), ["chunked"]);
+        assert_eq!(body, b"8\r\nstreamed\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn streams_are_read_in_for_http10() {
+        let response = misframed(Response::with_stream(StatusCode::Ok, &b"streamed"[..]));
+        let (head, body) = written(response, Version::Http10, false);
+
+        assert_eq!(values(&head, "Content-Length"), ["8"]);
+        assert!(values(&head, "Transfer-Encoding").is_empty());
+        assert_eq!(body, b"streamed");
+    }
+
+    #[test]
+    fn a_stream_with_a_length_is_sent_as_it_is() {
+        for version in [Version::Http10, Version::Http11] {
+            let response = Response::with_stream(StatusCode::Ok, &b"streamed and then some"[..])
+                .header("Content-Length", "8")
+                .header("Transfer-Encoding", "chunked");
+            let (head, body) = written(response, version, false);
+
+            assert_eq!(values(&head, "Content-Length"), ["8"]);
+            assert!(values(&head, "Transfer-Encoding").is_empty());
+            assert_eq!(body, b"streamed");
+        }
+    }
+
+    #[test]
+    fn only_a_plain_number_counts_as_a_streams_length() {
+        for length in ["+8", "-8", "8 8", "", "0x8"] {
+            let response = Response::with_stream(StatusCode::Ok, &b"streamed"[..]).header("Content-Length", length);
+            let (head, _) = written(response, Version::Http11, false);
+
+            assert!(values(&head, "Content-Length").is_empty(), "{:?}", length);
+            assert_eq!(values(&head, "Transfer-Encoding"), ["chunked"], "{:?}", length);
+        }
+    }
+}
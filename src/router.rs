@@ -0,0 +1,441 @@
+use crate::compression;
+use crate::conditional;
+use crate::range;
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::status::StatusCode;
+
+// alias trait object for a route handler. Send + Sync because the router is
+// shared between every worker thread in the pool.
+type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+
+enum Segment {
+    /// Has to match exactly, e.g. `users` in `/users/:id`.
+    Literal(String),
+    /// `:name`, matches any one segment and captures it.
+    Param(String),
+    /// `*name`, matches whatever is left of the path (even nothing) and captures it.
+    Rest(String)
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler
+}
+
+impl Route {
+    // returns the captured params if the path, split up and decoded into `parts`,
+    // fits this route's pattern
+    fn matches(&self, parts: &[String]) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut parts = parts.iter();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                },
+                Segment::Param(name) => {
+                    params.push((name.clone(), parts.next()?.clone()));
+                },
+                Segment::Rest(name) => {
+                    let rest: Vec<&str> = parts.by_ref().map(String::as_str).collect();
+                    params.push((name.clone(), rest.join("/")));
+                }
+            }
+        }
+
+        // anything left over means the path is longer than the pattern
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params)
+        }
+    }
+}
+
+/// Picks a handler for each request based on its method and path.
+///
+/// Patterns are paths where a segment starting with `:` captures that one
+/// segment and a final segment starting with `*` captures the rest of the
+/// path, e.g. `/users/:id` or `/files/*path`. Captures end up in `Request::params`.
+/// Routes are tried in the order they were added.
+///
+/// Each segment of the request's path is percent-decoded before it's matched,
+/// so `/users/john%20doe` captures `john doe`, and a path with a broken escape
+/// (or one that doesn't decode to UTF-8) is answered with a 400.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler
+}
+
+impl Router {
+    /// Create a router with no routes, answering everything with a bare 404.
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_| Response::new(StatusCode::NotFound))
+        }
+    }
+
+    /// Add a handler for `method` requests to paths matching `pattern`.
+    ///
+    /// Panics if `pattern` doesn't start with `/` or has a `*` segment anywhere
+    /// but the end, since that's a mistake in the code setting up the routes.
+    pub fn route<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        assert!(pattern.starts_with('/'), "route pattern {:?} has to start with /", pattern);
+
+        let segments: Vec<Segment> = split_path(pattern)
+            .map(|part| {
+                if let Some(name) = part.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = part.strip_prefix('*') {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(part.to_string())
+                }
+            })
+            .collect();
+
+        let rest_position = segments.iter().position(|segment| matches!(segment, Segment::Rest(_)));
+        assert!(
+            rest_position.is_none_or(|position| position == segments.len() - 1),
+            "route pattern {:?} can only have a * segment at the end",
+            pattern
+        );
+
+        self.routes.push(Route { method, segments, handler: Box::new(handler) });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Use `handler` for requests that don't match any route.
+    pub fn not_found<F>(&mut self, handler: F) -> &mut Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Run whichever handler matches `request`, filling in its params first.
+    ///
+    /// If the path matches a route but the method doesn't, the answer is a 405
+    /// with an `Allow` header listing the methods that would have worked.
+    ///
+    /// Some methods are answered without needing a route of their own. HEAD
+    /// runs the GET handler for the path (the body gets left off when the
+    /// response is written with `Response::write_head_to`). OPTIONS gets an
+    /// `Allow` header for the path, or for the whole server with `OPTIONS *`.
+    ///
+    /// For GET and HEAD, whatever the handler answers with is then checked
+    /// against the request's `If-Match`, `If-None-Match`, `If-Modified-Since`
+    /// and `If-Unmodified-Since` headers, and turned into a 304 or 412 if they
+    /// call for it. A body held in memory gets an `ETag` hashed from it first
+    /// if the handler didn't set one. See `conditional::check` for other methods.
+    ///
+    /// Successful responses with a known length advertise `Accept-Ranges: bytes`,
+    /// and a GET with a `Range` header (and a matching `If-Range`, if it has one)
+    /// gets just the bytes it asked for as a 206, or a 416 if none of them exist.
+    ///
+    /// Text responses (going by `Content-Type`) of a worthwhile size with a body
+    /// that isn't a stream are compressed with gzip or deflate, whichever the
+    /// request's `Accept-Encoding` prefers, and get `Vary: Accept-Encoding`.
+    pub fn handle(&self, request: &mut Request) -> Response {
+        let mut response = self.dispatch(request);
+
+        // picked first since it changes the ETag, but only compressed once we
+        // know it isn't turning into a 304
+        let encoding = compression::negotiate(request, &mut response);
+        let response = conditional::evaluate(request, response);
+        let response = compression::encode(response, encoding);
+
+        range::evaluate(request, response)
+    }
+
+    fn dispatch(&self, request: &mut Request) -> Response {
+        // `OPTIONS *` asks about the server as a whole rather than any one path
+        if request.method == Method::Options && request.target == "*" {
+            let methods: Vec<Method> = self.routes.iter().map(|route| route.method).collect();
+            return allow(StatusCode::Ok, methods);
+        }
+
+        // decoded a segment at a time, so an escaped `/` stays inside its segment
+        let parts: Option<Vec<String>> = split_path(request.path()).map(percent_decode).collect();
+        let parts = match parts {
+            Some(parts) => parts,
+            None => return Response::new(StatusCode::BadRequest)
+        };
+
+        let mut allowed: Vec<Method> = Vec::new();
+        // the GET route to fall back on for a HEAD request with no HEAD route of its own
+        let mut get_for_head = None;
+
+        for route in &self.routes {
+            let params = match route.matches(&parts) {
+                Some(params) => params,
+                None => continue
+            };
+
+            if route.method == request.method {
+                request.params = params.into_iter().collect();
+                return (route.handler)(request);
+            }
+
+            if request.method == Method::Head && route.method == Method::Get && get_for_head.is_none() {
+                get_for_head = Some((route, params));
+            }
+
+            allowed.push(route.method);
+        }
+
+        if let Some((route, params)) = get_for_head {
+            request.params = params.into_iter().collect();
+            return (route.handler)(request);
+        }
+
+        if allowed.is_empty() {
+            return (self.not_found)(request);
+        }
+
+        if request.method == Method::Options {
+            return allow(StatusCode::Ok, allowed);
+        }
+
+        allow(StatusCode::MethodNotAllowed, allowed)
+    }
+}
+
+// an empty response with an `Allow` header for `methods`, plus the ones we answer for ourselves
+fn allow(status: StatusCode, mut methods: Vec<Method>) -> Response {
+    if methods.contains(&Method::Get) {
+        methods.push(Method::Head);
+    }
+    methods.push(Method::Options);
+
+    let mut names: Vec<&str> = Vec::new();
+    for method in methods {
+        if !names.contains(&method.as_str()) {
+            names.push(method.as_str());
+        }
+    }
+
+    Response::new(status).header("Allow", &names.join(", "))
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+// `/users/1/` and `/users/1` are treated as the same path
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty())
+}
+
+// decode %xx escapes, None if one is malformed or the result isn't utf-8
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Body;
+    use crate::static_files::StaticFiles;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: x\r\n\r\n", method, target);
+        Request::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    // the status and body of what `router` answers with
+    fn send(router: &Router, method: &str, target: &str) -> (StatusCode, String) {
+        let response = router.handle(&mut request(method, target));
+        let body = match response.body {
+            Body::Bytes(contents) => String::from_utf8(contents).unwrap(),
+            _ => panic!("expected a body held in memory")
+        };
+        (response.status, body)
+    }
+
+    // answers with whatever got captured for `name`
+    fn echo(router: &mut Router, pattern: &str, name: &'static str) {
+        router.get(pattern, move |request| {
+            Response::with_body(StatusCode::Ok, request.param(name).unwrap_or("<none>"))
+        });
+    }
+
+    #[test]
+    fn captures_params_and_the_rest() {
+        let mut router = Router::new();
+        echo(&mut router, "/users/:id", "id");
+        echo(&mut router, "/files/*path", "path");
+
+        assert_eq!(send(&router, "GET", "/users/42"), (StatusCode::Ok, "42".to_string()));
+        assert_eq!(send(&router, "GET", "/files/a/b/c.txt"), (StatusCode::Ok, "a/b/c.txt".to_string()));
+        assert_eq!(send(&router, "GET", "/files"), (StatusCode::Ok, "".to_string()));
+        assert_eq!(send(&router, "GET", "/files/?x=1"), (StatusCode::Ok, "".to_string()));
+
+        assert_eq!(send(&router, "GET", "/users").0, StatusCode::NotFound);
+        assert_eq!(send(&router, "GET", "/users/42/posts").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn literals_have_to_match_exactly() {
+        let mut router = Router::new();
+        echo(&mut router, "/users/me", "id");
+        echo(&mut router, "/users/:id", "id");
+
+        // routes are tried in order, so the literal wins
+        assert_eq!(send(&router, "GET", "/users/me"), (StatusCode::Ok, "<none>".to_string()));
+        assert_eq!(send(&router, "GET", "/users/Me"), (StatusCode::Ok, "Me".to_string()));
+        assert_eq!(send(&router, "GET", "/Users/me").0, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn trailing_and_doubled_slashes_dont_matter() {
+        let mut router = Router::new();
+        echo(&mut router, "/users/:id/", "id");
+
+        for target in ["/users/42", "/users/42/", "//users//42", "/users/42?page=2"] {
+            assert_eq!(send(&router, "GET", target), (StatusCode::Ok, "42".to_string()), "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn segments_are_decoded_before_matching() {
+        let mut router = Router::new();
+        echo(&mut router, "/café/:id", "id");
+        echo(&mut router, "/files/*path", "path");
+
+        assert_eq!(send(&router, "GET", "/caf%C3%A9/john%20doe"), (StatusCode::Ok, "john doe".to_string()));
+        // an escaped slash is part of the one segment, not a new one
+        assert_eq!(send(&router, "GET", "/caf%c3%a9/a%2Fb"), (StatusCode::Ok, "a/b".to_string()));
+        assert_eq!(send(&router, "GET", "/files/a%20b/%2e%2e"), (StatusCode::Ok, "a b/..".to_string()));
+        assert_eq!(send(&router, "GET", "/files/100%25"), (StatusCode::Ok, "100%".to_string()));
+    }
+
+    #[test]
+    fn broken_escapes_are_a_bad_request() {
+        let mut router = Router::new();
+        echo(&mut router, "/files/*path", "path");
+
+        for target in ["/files/%", "/files/%2", "/files/%zz", "/files/%+1", "/files/%ff", "/files/%C3%28"] {
+            assert_eq!(send(&router, "GET", target).0, StatusCode::BadRequest, "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn static_files_get_the_decoded_path() {
+        let dir = env::temp_dir().join(format!("rust-webserver-router-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root")).unwrap();
+        fs::write(dir.join("root/a b.txt"), "inside").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+        let files = StaticFiles::new(dir.join("root"));
+        let mut router = Router::new();
+        router.get("/*path", move |request| {
+            files.serve(request.param("path").unwrap_or("")).unwrap_or_else(|| Response::new(StatusCode::NotFound))
+        });
+
+        let status = |target: &str| router.handle(&mut request("GET", target)).status;
+        assert_eq!(status("/a%20b.txt"), StatusCode::Ok);
+        assert_eq!(status("/%2e%2e/secret.txt"), StatusCode::Forbidden);
+        assert_eq!(status("/x%2F..%2F..%2Fsecret.txt"), StatusCode::Forbidden);
+        assert_eq!(status("/..%5Csecret.txt"), StatusCode::Forbidden);
+        assert_eq!(status("/a%20b.txt%00"), StatusCode::Forbidden);
+        assert_eq!(status("/%ff"), StatusCode::BadRequest);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wrong_method_is_a_405_listing_the_right_ones() {
+        let mut router = Router::new();
+        router.get("/items", |_| Response::new(StatusCode::Ok));
+        router.post("/items", |_| Response::new(StatusCode::Ok));
+        router.delete("/items/:id", |_| Response::new(StatusCode::Ok));
+
+        let response = router.handle(&mut request("PUT", "/items"));
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("GET, POST, HEAD, OPTIONS"));
+
+        // no GET means no HEAD either
+        let response = router.handle(&mut request("GET", "/items/1"));
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("DELETE, OPTIONS"));
+
+        let response = router.handle(&mut request("OPTIONS", "/items"));
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.headers.get("Allow"), Some("GET, POST, HEAD, OPTIONS"));
+
+        let response = router.handle(&mut request("OPTIONS", "*"));
+        assert_eq!(response.headers.get("Allow"), Some("GET, POST, DELETE, HEAD, OPTIONS"));
+
+        assert_eq!(router.handle(&mut request("PUT", "/nothing")).status, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn head_falls_back_on_the_get_route() {
+        let mut router = Router::new();
+        echo(&mut router, "/users/:id", "id");
+
+        assert_eq!(send(&router, "HEAD", "/users/7"), (StatusCode::Ok, "7".to_string()));
+    }
+}
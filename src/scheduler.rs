@@ -0,0 +1,245 @@
+//! Running jobs later, or over and over, on a `ThreadPool`.
+//!
+//! One scheduler thread per pool keeps the timers in a heap ordered by when
+//! they're next due, and sleeps until the soonest one. It never runs the jobs
+//! itself, it just hands them to the pool's workers when they come due, so a
+//! slow job can't hold up the rest of the timers.
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Job, Priority, Shared};
+
+/// A handle to a job scheduled with `ThreadPool::execute_after` or
+/// `ThreadPool::execute_every`, for calling it off.
+///
+/// Dropping the handle doesn't cancel anything, the job still runs.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>
+}
+
+impl TimerHandle {
+    /// Stop the job from running again. A run that has already started
+    /// carries on to the end, only ones that haven't started yet are called off.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+enum Task {
+    Once(Job),
+    Every(Arc<Periodic>)
+}
+
+struct Periodic {
+    f: Box<dyn Fn() + Send + Sync + 'static>,
+    interval: Duration,
+    // set while a run is queued or going, so a run that's slower than the
+    // interval doesn't end up with several copies of itself going at once
+    running: AtomicBool
+}
+
+struct Timer {
+    due: Instant,
+    // ties on `due` go in the order they were scheduled
+    seq: u64,
+    task: Task,
+    cancelled: Arc<AtomicBool>
+}
+
+// BinaryHeap is a max heap, so these are backwards to get the soonest timer on top
+impl Ord for Timer {
+    fn cmp(&self, other: &Timer) -> CmpOrdering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Timer {}
+
+struct Timers {
+    heap: BinaryHeap<Timer>,
+    next_seq: u64,
+    stopped: bool
+}
+
+// the half of the scheduler the thread and the pool share
+struct State {
+    timers: Mutex<Timers>,
+    // signalled when a timer is added that might be due sooner, or when stopping
+    changed: Condvar
+}
+
+pub(crate) struct Scheduler {
+    state: Arc<State>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+impl Scheduler {
+    pub(crate) fn start(shared: Arc<Shared>) -> io::Result<Scheduler> {
+        let state = Arc::new(State {
+            timers: Mutex::new(Timers { heap: BinaryHeap::new(), next_seq: 0, stopped: false }),
+            changed: Condvar::new()
+        });
+
+        let thread_state = Arc::clone(&state);
+        let thread = thread::Builder::new()
+            .name(format!("{}scheduler", shared.thread_name_prefix))
+            .spawn(move || run(&thread_state, &shared))?;
+
+        Ok(Scheduler { state, thread: Some(thread) })
+    }
+
+    pub(crate) fn once(&self, delay: Duration, job: Job) -> TimerHandle {
+        self.add(Instant::now() + delay, Task::Once(job))
+    }
+
+    pub(crate) fn every(&self, interval: Duration, f: Box<dyn Fn() + Send + Sync + 'static>) -> TimerHandle {
+        let periodic = Periodic { f, interval, running: AtomicBool::new(false) };
+        self.add(Instant::now() + interval, Task::Every(Arc::new(periodic)))
+    }
+
+    fn add(&self, due: Instant, task: Task) -> TimerHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let mut timers = self.state.timers.lock().unwrap();
+        let seq = timers.next_seq;
+        timers.next_seq += 1;
+        timers.heap.push(Timer { due, seq, task, cancelled: Arc::clone(&cancelled) });
+
+        // it might be due before whatever the thread is sleeping until
+        self.state.changed.notify_one();
+
+        TimerHandle { cancelled }
+    }
+
+    // stop the thread and throw away any timers that haven't fired yet
+    pub(crate) fn stop(&mut self) {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return
+        };
+
+        self.state.timers.lock().unwrap().stopped = true;
+        self.state.changed.notify_one();
+
+        if let Err(payload) = thread.join() {
+            println!("Scheduler died: {}", crate::panic_message(&*payload));
+        }
+    }
+}
+
+fn run(state: &State, shared: &Arc<Shared>) {
+    let mut timers = state.timers.lock().unwrap();
+
+    loop {
+        if timers.stopped {
+            return;
+        }
+
+        let now = Instant::now();
+        let due = match timers.heap.peek() {
+            Some(timer) => timer.due,
+            None => {
+                timers = state.changed.wait(timers).unwrap();
+                continue;
+            }
+        };
+
+        if due > now {
+            timers = state.changed.wait_timeout(timers, due - now).unwrap().0;
+            continue;
+        }
+
+        let timer = timers.heap.pop().unwrap();
+        if timer.cancelled.load(Ordering::SeqCst) {
+            continue;
+        }
+
+        let job = match timer.task {
+            Task::Once(job) => Some(cancellable(job, &timer.cancelled)),
+            Task::Every(periodic) => {
+                // skip this run if the last one is still going
+                let job = if periodic.running.swap(true, Ordering::SeqCst) {
+                    None
+                } else {
+                    Some(periodic_job(&periodic, &timer.cancelled))
+                };
+
+                // keep to the original schedule, but if we've fallen more than a
+                // whole interval behind don't fire a burst of runs to catch up
+                let mut next = timer.due + periodic.interval;
+                if next <= now {
+                    next = now + periodic.interval;
+                }
+
+                timers.heap.push(Timer { due: next, task: Task::Every(periodic), ..timer });
+                job
+            }
+        };
+
+        // handing it over can mean spawning a worker, don't hold up execute_after meanwhile
+        if let Some(job) = job {
+            drop(timers);
+            dispatch(shared, job);
+            timers = state.timers.lock().unwrap();
+        }
+    }
+}
+
+// cancelling after a job's been handed to the pool still stops it, as long as it hasn't started
+fn cancellable(job: Job, cancelled: &Arc<AtomicBool>) -> Job {
+    let cancelled = Arc::clone(cancelled);
+
+    Box::new(move || {
+        if !cancelled.load(Ordering::SeqCst) {
+            job();
+        }
+    })
+}
+
+fn periodic_job(periodic: &Arc<Periodic>, cancelled: &Arc<AtomicBool>) -> Job {
+    let periodic = Arc::clone(periodic);
+
+    cancellable(
+        Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| (periodic.f)()));
+            periodic.running.store(false, Ordering::SeqCst);
+
+            // let the worker deal with it like any other job that panics
+            if let Err(payload) = result {
+                panic::resume_unwind(payload);
+            }
+        }),
+        cancelled
+    )
+}
+
+// timers skip the bounded queue's capacity check. they're few, and one that got
+// turned away would just be lost, or hold up every other timer while it waited.
+fn dispatch(shared: &Arc<Shared>, job: Job) {
+    shared.backlog.force_reserve();
+    shared.submit(job, Priority::Normal);
+}
@@ -0,0 +1,186 @@
+use std::any::Any;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::{Job, Shared, ThreadPool};
+
+/// Lets jobs borrow from the stack of whoever called `ThreadPool::scope`.
+///
+/// Every job started on a scope has finished by the time `scope` returns,
+/// which is what makes it fine for them to borrow things that only live
+/// as long as the call.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    state: Arc<State>,
+    // same trick as std::thread::Scope, keeps both lifetimes from being shortened or lengthened
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>
+}
+
+#[derive(Default)]
+struct State {
+    jobs: Mutex<Jobs>,
+    // signalled when the last outstanding job is done
+    done: Condvar
+}
+
+#[derive(Default)]
+struct Jobs {
+    outstanding: usize,
+    // the first job to panic, handed on to whoever called scope
+    panic: Option<Box<dyn Any + Send + 'static>>
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub(crate) fn new(pool: &'env ThreadPool) -> Scope<'scope, 'env> {
+        Scope { pool, state: Arc::new(State::default()), scope: PhantomData, env: PhantomData }
+    }
+
+    // block until every job started on the scope is done, and hand back the first one to panic if any did
+    pub(crate) fn wait(&self) -> Option<Box<dyn Any + Send + 'static>> {
+        let mut jobs = self.state.jobs.lock().unwrap();
+        while jobs.outstanding > 0 {
+            jobs = self.state.done.wait(jobs).unwrap();
+        }
+        jobs.panic.take()
+    }
+
+    /// Run `f` on the pool. Unlike `ThreadPool::execute` it can borrow
+    /// anything that outlives the scope.
+    ///
+    /// If it panics, the panic is passed on by `scope` once every other job has finished.
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope
+    {
+        self.state.jobs.lock().unwrap().outstanding += 1;
+
+        let job = ScopedJob {
+            f,
+            shared: Arc::clone(&self.pool.shared),
+            done: Done { state: Arc::clone(&self.state) }
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || job.run());
+
+        // the pool only takes 'static jobs. this one isn't, but `scope` doesn't return
+        // until it's been run or dropped (Done sees to that either way), so nothing it
+        // borrows can go away while it's still around.
+        let job: Job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+        self.pool.execute(job);
+    }
+}
+
+// fields drop in order, so whatever `f` borrows is let go of before Done says it's finished
+struct ScopedJob<F> {
+    f: F,
+    shared: Arc<Shared>,
+    done: Done
+}
+
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(self) {
+        let ScopedJob { f, shared, done } = self;
+
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            // the worker never sees this panic, so count it here instead
+            shared.panicked.fetch_add(1, Ordering::SeqCst);
+            done.state.jobs.lock().unwrap().panic.get_or_insert(payload);
+        }
+    }
+}
+
+// counts the job as finished when dropped, whether it ran, panicked or was thrown away
+struct Done {
+    state: Arc<State>
+}
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        let mut jobs = self.state.jobs.lock().unwrap();
+        jobs.outstanding -= 1;
+
+        if jobs.outstanding == 0 {
+            self.state.done.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ThreadPool;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn message(payload: Box<dyn std::any::Any + Send>) -> String {
+        payload.downcast_ref::<&str>().map(|message| message.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn jobs_borrowing_the_stack_finish_before_scope_returns() {
+        let pool = ThreadPool::new(4);
+        let mut slots = vec![0; 16];
+        let finished = AtomicUsize::new(0);
+
+        pool.scope(|scope| {
+            for (i, slot) in slots.iter_mut().enumerate() {
+                let finished = &finished;
+                scope.execute(move || {
+                    // slow enough that scope would get back first if it didn't wait
+                    thread::sleep(Duration::from_millis(10));
+                    *slot = i + 1;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        assert_eq!(finished.load(Ordering::SeqCst), 16);
+        assert_eq!(slots, (1..=16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_job_panic_is_passed_on_once_the_rest_finish() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.execute(|| panic!("the job panicked"));
+                for _ in 0..4 {
+                    scope.execute(|| {
+                        thread::sleep(Duration::from_millis(20));
+                        finished.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+            })
+        }));
+
+        assert_eq!(message(result.unwrap_err()), "the job panicked");
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+        // and the pool is still fine to use afterwards
+        assert_eq!(pool.scope(|_| 7), 7);
+    }
+
+    #[test]
+    fn a_panic_in_f_still_waits_for_the_jobs_it_started() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicBool::new(false);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.execute(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.store(true, Ordering::SeqCst);
+                });
+                panic!("f panicked");
+            })
+        }));
+
+        assert_eq!(message(result.unwrap_err()), "f panicked");
+        assert!(finished.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,72 @@
+//! Noticing when we've been asked to shut down.
+//!
+//! Catches SIGINT (Ctrl-C) and SIGTERM (what `docker stop` and friends send)
+//! and just flips a flag, so the accept loop can check it and stop cleanly
+//! instead of the process being killed with requests still in flight.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// how often wait_for_shutdown checks the flag. a signal handler can't wake
+// anything up safely, so there's nothing to do but look every so often.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Start catching SIGINT and SIGTERM. After this, those signals no longer
+/// kill the process, they make `shutdown_requested` return true.
+///
+/// Does nothing on platforms without unix signals.
+pub fn listen_for_shutdown() {
+    #[cfg(unix)]
+    unix::install();
+}
+
+/// Whether a shutdown signal has come in since `listen_for_shutdown` was called.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Block until a shutdown has been requested.
+pub fn wait_for_shutdown() {
+    while !shutdown_requested() {
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
+/// Ask for a shutdown the same way a signal would, e.g. from an admin endpoint.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::os::raw::c_int;
+
+    // same numbers on linux and the bsds / macos
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+
+    // std already links against libc, so we can call signal(2) without pulling in a crate for it.
+    // sighandler_t is just a function pointer, which is pointer sized.
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    // runs inside the signal handler, where almost nothing is safe to do.
+    // storing to an atomic is, so that's all this does.
+    extern "C" fn handle(_signum: c_int) {
+        super::request_shutdown();
+    }
+
+    pub fn install() {
+        let handler = handle as extern "C" fn(c_int) as usize;
+
+        // signal only fails for invalid signal numbers, and these are fine
+        unsafe {
+            signal(SIGINT, handler);
+            signal(SIGTERM, handler);
+        }
+    }
+}
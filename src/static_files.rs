@@ -0,0 +1,284 @@
+use std::fs::{File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::compression;
+use crate::date::http_date;
+use crate::request::Request;
+use crate::response::Response;
+use crate::status::StatusCode;
+
+/// Serves files out of a directory on disk.
+pub struct StaticFiles {
+    root: PathBuf,
+    // whether to look for a .gz next to each file
+    precompressed: bool
+}
+
+impl StaticFiles {
+    /// Serve files from under `root`, the document root.
+    pub fn new<P: Into<PathBuf>>(root: P) -> StaticFiles {
+        StaticFiles { root: root.into(), precompressed: false }
+    }
+
+    /// Look for a gzipped copy next to each file, e.g. `app.js.gz` next to
+    /// `app.js`, and send that to clients that take gzip instead of having it
+    /// compressed all over again on every request. Off by default.
+    ///
+    /// Only `serve_request` knows whether the client takes gzip, `serve` always
+    /// sends the file as it is.
+    pub fn precompressed(mut self, enabled: bool) -> StaticFiles {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Find the file for the url `path` (relative to the document root, and
+    /// already percent-decoded, like a `Router` capture) and build a response
+    /// streaming it.
+    ///
+    /// Directories are answered with the `index.html` inside them. Returns None
+    /// if there's nothing there, so the caller can fall back to its own 404.
+    /// Paths trying to climb out of the document root get a 403.
+    ///
+    /// The response has an `ETag` and `Last-Modified` worked out from the file's
+    /// size and modification time, so clients can revalidate what they've cached.
+    pub fn serve(&self, path: &str) -> Option<Response> {
+        self.serve_encoded(path, false)
+    }
+
+    /// The same as `serve`, but sends the precompressed copy of the file if
+    /// there is one and `request`'s `Accept-Encoding` says gzip is fine.
+    pub fn serve_request(&self, request: &Request, path: &str) -> Option<Response> {
+        self.serve_encoded(path, self.precompressed && compression::accepts_gzip(request))
+    }
+
+    fn serve_encoded(&self, path: &str, gzip: bool) -> Option<Response> {
+        let relative = match sanitize(path) {
+            Some(relative) => relative,
+            None => return Some(Response::new(StatusCode::Forbidden))
+        };
+
+        let mut full_path = self.root.join(relative);
+        if full_path.is_dir() {
+            full_path.push("index.html");
+        }
+
+        match self.open(&full_path) {
+            Ok(Some(file)) => {
+                let gzipped = if self.precompressed {
+                    self.open(&gzipped_path(&full_path)).ok().flatten()
+                } else {
+                    None
+                };
+                // it varies with Accept-Encoding whether or not this client gets the .gz
+                let varies = gzipped.is_some();
+
+                let (file, encoding) = match gzipped {
+                    Some(gzipped) if gzip => (gzipped, Some("gzip")),
+                    _ => (file, None)
+                };
+
+                let metadata = match file.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        println!("Couldn't read {}: {}", full_path.display(), err);
+                        return Some(Response::new(StatusCode::InternalServerError));
+                    }
+                };
+
+                let mut response = Response::with_file(StatusCode::Ok, file);
+                response.headers.insert("Content-Type", content_type(&full_path));
+                if let Some(encoding) = encoding {
+                    response.headers.insert("Content-Encoding", encoding);
+                }
+                if varies {
+                    compression::add_vary(&mut response.headers, "Accept-Encoding");
+                }
+                add_validators(&mut response, &metadata);
+                Some(response)
+            },
+            Ok(None) => Some(Response::new(StatusCode::Forbidden)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => {
+                println!("Couldn't open {}: {}", full_path.display(), err);
+                Some(Response::new(StatusCode::InternalServerError))
+            }
+        }
+    }
+
+    // open the file, but only if it really lives under the root. sanitize already
+    // keeps `..` out of the path, this catches symlinks pointing somewhere else.
+    fn open(&self, path: &Path) -> io::Result<Option<File>> {
+        let root = self.root.canonicalize()?;
+        let path = path.canonicalize()?;
+
+        if !path.starts_with(&root) || !path.is_file() {
+            return Ok(None);
+        }
+
+        File::open(path).map(Some)
+    }
+}
+
+// `app.js` to `app.js.gz`
+fn gzipped_path(path: &Path) -> PathBuf {
+    let mut gzipped = path.as_os_str().to_owned();
+    gzipped.push(".gz");
+    PathBuf::from(gzipped)
+}
+
+// ETag and Last-Modified for a file. the ETag is built from the size and the
+// modification time down to the nanosecond, so it changes even when an edit
+// lands in the same second, which Last-Modified can't tell apart.
+fn add_validators(response: &mut Response, metadata: &Metadata) {
+    let modified = match metadata.modified() {
+        Ok(modified) => modified,
+        // not every platform keeps track, there's nothing to go on without it
+        Err(_) => return
+    };
+
+    let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", metadata.len(), since_epoch.as_nanos());
+
+    response.headers.insert("ETag", &etag);
+    response.headers.insert("Last-Modified", &http_date(modified));
+}
+
+/// Guess a `Content-Type` from a file's extension, falling back to
+/// `application/octet-stream` for anything we don't recognise.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("pdf") => "application/pdf",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        _ => "application/octet-stream"
+    }
+}
+
+// turn a decoded url path into a relative file path, or None if it tries to get out of the root.
+// it has to be decoded first so `%2e%2e` can't sneak a `..` past the check.
+fn sanitize(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            // a segment can't smuggle in its own separator or a null byte either
+            segment if segment.contains('\\') || segment.contains('\0') => return None,
+            segment => relative.push(segment)
+        }
+    }
+
+    Some(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // a document root with one file in it, and a secret sitting just outside it
+    struct Site {
+        dir: PathBuf
+    }
+
+    impl Site {
+        fn new(name: &str) -> Site {
+            let dir = env::temp_dir().join(format!("rust-webserver-static-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("root/sub")).unwrap();
+            fs::write(dir.join("root/index.html"), "index").unwrap();
+            fs::write(dir.join("root/sub/file.txt"), "file").unwrap();
+            fs::write(dir.join("secret.txt"), "secret").unwrap();
+            Site { dir }
+        }
+
+        fn files(&self) -> StaticFiles {
+            StaticFiles::new(self.dir.join("root"))
+        }
+
+        fn status(&self, path: &str) -> Option<StatusCode> {
+            self.files().serve(path).map(|response| response.status)
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn sanitize_keeps_paths_inside_the_root() {
+        assert_eq!(sanitize("/sub//./file.txt"), Some(PathBuf::from("sub/file.txt")));
+        assert_eq!(sanitize(""), Some(PathBuf::new()));
+        assert_eq!(sanitize("..."), Some(PathBuf::from("...")));
+
+        for path in ["..", "/../secret", "sub/../../secret", "sub/..", "..\\secret", "sub\\file.txt", "file\0.txt"] {
+            assert_eq!(sanitize(path), None, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn serves_what_is_under_the_root() {
+        let site = Site::new("inside");
+
+        assert_eq!(site.status("sub/file.txt"), Some(StatusCode::Ok));
+        assert_eq!(site.status("/sub//file.txt"), Some(StatusCode::Ok));
+        assert_eq!(site.status(""), Some(StatusCode::Ok));
+        assert_eq!(site.status("sub/missing.txt"), None);
+    }
+
+    #[test]
+    fn forbids_climbing_out_of_the_root() {
+        let site = Site::new("traversal");
+
+        for path in ["../secret.txt", "sub/../../secret.txt", "..\\secret.txt", "sub/file.txt\0.html"] {
+            assert_eq!(site.status(path), Some(StatusCode::Forbidden), "{:?}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn forbids_symlinks_pointing_out_of_the_root() {
+        let site = Site::new("symlink");
+        std::os::unix::fs::symlink(site.dir.join("secret.txt"), site.dir.join("root/link.txt")).unwrap();
+        std::os::unix::fs::symlink(&site.dir, site.dir.join("root/up")).unwrap();
+        // one that stays inside is fine
+        std::os::unix::fs::symlink(site.dir.join("root/sub/file.txt"), site.dir.join("root/alias.txt")).unwrap();
+
+        assert_eq!(site.status("link.txt"), Some(StatusCode::Forbidden));
+        assert_eq!(site.status("up/secret.txt"), Some(StatusCode::Forbidden));
+        assert_eq!(site.status("alias.txt"), Some(StatusCode::Ok));
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// A snapshot of what a `ThreadPool` is up to, from `ThreadPool::stats`.
+///
+/// The numbers are read one after another while the pool keeps running, so
+/// they can be very slightly out of step with each other.
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    /// Jobs waiting for a worker to pick them up.
+    pub queued: usize,
+    /// Worker threads the pool has right now.
+    pub workers: usize,
+    /// Workers in the middle of running a job.
+    pub active: usize,
+    /// Jobs that have run to the end without panicking, since the pool was created.
+    pub completed: usize,
+    /// Jobs that panicked, since the pool was created.
+    pub panicked: usize,
+    /// Jobs thrown away without running because their `CancellationToken`
+    /// was cancelled (or its deadline passed) while they were queued.
+    pub cancelled: usize,
+    /// Jobs whose token's deadline passed before they were done, whether
+    /// they were still queued or already running.
+    pub timed_out: usize,
+    /// One entry for each worker thread the pool has right now.
+    pub per_worker: Vec<WorkerStats>
+}
+
+/// How busy a single worker thread has been.
+#[derive(Debug, Clone)]
+pub struct WorkerStats {
+    pub id: usize,
+    /// Whether it's running a job right now.
+    pub busy: bool,
+    /// Jobs it has finished, panicked or not.
+    pub jobs: usize,
+    /// Total time spent running jobs, not counting the one it's running right now.
+    pub busy_time: Duration
+}
+
+// the live counters behind a WorkerStats, updated by the worker thread as it goes
+#[derive(Default)]
+pub(crate) struct WorkerCounters {
+    busy: AtomicBool,
+    jobs: AtomicUsize,
+    busy_nanos: AtomicU64
+}
+
+impl WorkerCounters {
+    pub(crate) fn start_job(&self) {
+        self.busy.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn finish_job(&self, took: Duration) {
+        self.jobs.fetch_add(1, Ordering::SeqCst);
+        self.busy_nanos.fetch_add(took.as_nanos() as u64, Ordering::SeqCst);
+        self.busy.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn snapshot(&self, id: usize) -> WorkerStats {
+        WorkerStats {
+            id,
+            busy: self.busy.load(Ordering::SeqCst),
+            jobs: self.jobs.load(Ordering::SeqCst),
+            busy_time: Duration::from_nanos(self.busy_nanos.load(Ordering::SeqCst))
+        }
+    }
+}